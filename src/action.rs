@@ -0,0 +1,408 @@
+use crate::config::SettingsReader;
+use crate::event::Event;
+use crate::keymatch::KeyMatch;
+use crate::mode::{self, Mode};
+use crossbeam_channel::{select, Receiver, Sender};
+use std::process::{Child, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    /// run with `sh -c`
+    Shell(String),
+    Argv(Vec<String>),
+}
+
+impl Command {
+    fn spawn(&self, e: &Event) -> std::io::Result<Child> {
+        let mut cmd = match self {
+            Command::Shell(s) => {
+                let mut c = std::process::Command::new("sh");
+                c.arg("-c").arg(s);
+                c
+            }
+            Command::Argv(v) => {
+                let mut c = std::process::Command::new(&v[0]);
+                c.args(&v[1..]);
+                c
+            }
+        };
+        cmd.env("LIRC_CODE", format!("{:016x}", e.code))
+            .env("LIRC_REPEAT", e.repeat.to_string())
+            .env("LIRC_NAME", &e.name)
+            .env("LIRC_DEVICE", &e.device)
+            .spawn()
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Command::Shell(s) => write!(f, "{}", s),
+            Command::Argv(v) => write!(f, "{}", v.join(" ")),
+        }
+    }
+}
+
+/// Command run when a matching event is sent to clients
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Action {
+    /// `None` name matches any key
+    pub key: KeyMatch,
+    /// 0 - run on the first event of a press only, n - on every n-th repeat
+    pub repeat: u32,
+    /// repeats ignored after the first event of a press
+    pub delay: u32,
+    /// run in turn, one each time the action fires
+    pub commands: Vec<Command>,
+    pub timeout: Duration,
+    /// no later action runs on the event, as lircrc's `quit` flag
+    pub quit: bool,
+    /// the action runs only the first time, as lircrc's `once` flag
+    pub once: bool,
+    /// mode entered once the command is started, as by a lircrc entry with
+    /// `mode` and `config`
+    pub switch: Option<String>,
+}
+
+impl Action {
+    fn matches(&self, mode: &str, e: &Event) -> bool {
+        self.key.matches(mode, &e.device, &e.name) && self.fires(e.repeat)
+    }
+
+    /// Same as lirc_client's `repeat` and `delay`: the first repeat after
+    /// `delay` fires, then every `repeat`-th one
+    fn fires(&self, repeat: u32) -> bool {
+        match repeat {
+            0 => true,
+            r if self.repeat == 0 || r <= self.delay => false,
+            r => (r - self.delay - 1).is_multiple_of(self.repeat),
+        }
+    }
+}
+
+/// Runs `command` with the event fields in `LIRC_CODE`, `LIRC_REPEAT`,
+/// `LIRC_NAME` and `LIRC_DEVICE`. Kills it after `timeout`.
+pub fn execute(command: &Command, e: &Event, timeout: Duration) -> Result<ExitStatus, String> {
+    let mut child = command
+        .spawn(e)
+        .map_err(|err| format!("can't start: {}", err))?;
+    let end = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return Ok(status),
+            Ok(None) => {}
+            Err(err) => return Err(format!("can't wait: {}", err)),
+        }
+        if Instant::now() >= end {
+            if let Err(err) = child.kill() {
+                log::warn!("Can't kill '{}': {}", command, err);
+            }
+            let _ = child.wait();
+            return Err(format!("timeout after {} ms", timeout.as_millis()));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Starts configured actions in background threads, at most `max` at once
+pub struct Actions {
+    actions: Vec<Action>,
+    /// times every action was started
+    turns: Vec<usize>,
+    max: usize,
+    running: Arc<AtomicUsize>,
+    mode: Mode,
+}
+
+impl Actions {
+    pub fn new(actions: Vec<Action>, max: usize, mode: Mode) -> Self {
+        Actions {
+            turns: vec![0; actions.len()],
+            actions,
+            max,
+            running: Arc::new(AtomicUsize::new(0)),
+            mode,
+        }
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Replaces the actions, their turns start again
+    pub fn update(&mut self, actions: Vec<Action>, max: usize) {
+        self.turns = vec![0; actions.len()];
+        self.actions = actions;
+        self.max = max;
+    }
+
+    /// Starts the actions matching the event, returns how many were started
+    /// and the announcement of the mode entered by them. Actions over the
+    /// limit are skipped, not queued, but still switch the mode.
+    pub fn start(&mut self, e: &Event) -> (usize, Option<Event>) {
+        let mut res = 0;
+        let mut switch = None;
+        let mode = self.mode.get();
+        for (a, turn) in self.actions.iter().zip(self.turns.iter_mut()) {
+            if !a.matches(&mode, e) || (a.once && *turn > 0) {
+                continue;
+            }
+            if a.switch.is_some() {
+                switch = a.switch.as_deref();
+            }
+            let command = a.commands[*turn % a.commands.len()].clone();
+            if self.running.fetch_add(1, Ordering::SeqCst) >= self.max {
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                log::warn!("Too many running actions, skip '{}'", command);
+            } else {
+                *turn += 1;
+                let (timeout, e, running) = (a.timeout, e.clone(), self.running.clone());
+                thread::spawn(move || {
+                    log::info!("Run '{}' on {}", command, e.name);
+                    match execute(&command, &e, timeout) {
+                        Ok(status) if status.success() => {
+                            log::info!("'{}' finished: {}", command, status)
+                        }
+                        Ok(status) => log::warn!("'{}' failed: {}", command, status),
+                        Err(err) => log::error!("'{}': {}", command, err),
+                    }
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+                res += 1;
+            }
+            if a.quit {
+                break;
+            }
+        }
+        let announce = switch.and_then(|m| self.mode.set(m).then(|| mode::announce(m)));
+        (res, announce)
+    }
+
+    /// Passes events from `data` to `out` running actions on them, until
+    /// `data` or `cl` is closed
+    pub fn run<T: From<Event>>(
+        mut self,
+        data: Receiver<Event>,
+        out: Sender<T>,
+        mut settings: SettingsReader,
+        cl: Receiver<u32>,
+    ) {
+        loop {
+            select! {
+                recv(data) -> msg => {
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Some(s) = settings.changed() {
+                        log::info!("update actions");
+                        self.update(s.actions.clone(), s.max_actions);
+                    }
+                    let (_, announce) = self.start(&received);
+                    out.send(received.into()).unwrap();
+                    if let Some(a) = announce {
+                        out.send(a.into()).unwrap();
+                    }
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in actions");
+                    break;
+                }
+            }
+        }
+        log::info!("exit actions");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::action::{execute, Action, Actions, Command};
+    use crate::event::Event;
+    use crate::keymatch::KeyMatch;
+    use crate::mode::{self, Mode};
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn ev(line: &str) -> Event {
+        Event::from_str(line).unwrap()
+    }
+
+    fn shell(s: &str) -> Command {
+        Command::Shell(String::from(s))
+    }
+
+    fn action(name: &str, command: Command) -> Action {
+        Action {
+            key: KeyMatch::name(name),
+            repeat: 0,
+            delay: 0,
+            commands: vec![command],
+            timeout: Duration::from_secs(5),
+            quit: false,
+            once: false,
+            switch: None,
+        }
+    }
+
+    #[test]
+    fn env() {
+        let e = ev("800f7422 1 KEY_POWER_HOLD mceusb");
+        let cmd = shell(
+            "test \"$LIRC_NAME $LIRC_DEVICE $LIRC_CODE $LIRC_REPEAT\" = \
+            'KEY_POWER_HOLD mceusb 00000000800f7422 1'",
+        );
+        assert!(execute(&cmd, &e, Duration::from_secs(5)).unwrap().success());
+    }
+
+    #[test]
+    fn exit_status() {
+        let e = ev("1 0 A d");
+        let st = execute(&shell("exit 3"), &e, Duration::from_secs(5)).unwrap();
+        assert_eq!(st.code(), Some(3));
+        let argv = Command::Argv(vec![String::from("true")]);
+        assert!(execute(&argv, &e, Duration::from_secs(5))
+            .unwrap()
+            .success());
+        let argv = Command::Argv(vec![String::from("/nonexistent/cmd")]);
+        assert!(execute(&argv, &e, Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn timeout() {
+        let now = Instant::now();
+        let res = execute(
+            &shell("sleep 5"),
+            &ev("1 0 A d"),
+            Duration::from_millis(100),
+        );
+        assert_eq!(res, Err(String::from("timeout after 100 ms")));
+        assert!(now.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn matches() {
+        let mut a = action("KEY_OK", shell("true"));
+        assert!(a.matches("default", &ev("1 0 KEY_OK d")));
+        assert!(!a.matches("default", &ev("1 1 KEY_OK d")));
+        assert!(!a.matches("default", &ev("1 0 KEY_UP d")));
+        a.repeat = 1;
+        a.key.device = Some(String::from("mce"));
+        assert!(a.matches("default", &ev("1 1 KEY_OK mce")));
+        assert!(!a.matches("default", &ev("1 0 KEY_OK d")));
+        a.key.name = None;
+        assert!(a.matches("default", &ev("1 0 KEY_UP mce")));
+        a.key.name = Some(String::from("key_ok"));
+        assert!(!a.matches("default", &ev("1 0 KEY_OK mce")));
+        a.key.ignore_case = true;
+        assert!(a.matches("default", &ev("1 0 KEY_OK mce")));
+        // lircrc remotes match in any case too
+        assert!(a.matches("default", &ev("1 0 KEY_OK MCE")));
+        a.key.mode = Some(String::from("tv"));
+        assert!(!a.matches("default", &ev("1 0 KEY_OK mce")));
+    }
+
+    #[test]
+    fn repeat_delay() {
+        let mut a = action("KEY_OK", shell("true"));
+        a.repeat = 2;
+        a.delay = 3;
+        let fired = (0..10).filter(|r| a.fires(*r)).collect::<Vec<_>>();
+        assert_eq!(fired, vec![0, 4, 6, 8]);
+        a.delay = 0;
+        let fired = (0..5).filter(|r| a.fires(*r)).collect::<Vec<_>>();
+        assert_eq!(fired, vec![0, 1, 3]);
+        a.repeat = 1;
+        let fired = (0..3).filter(|r| a.fires(*r)).collect::<Vec<_>>();
+        assert_eq!(fired, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn mode() {
+        let mut a = action("KEY_OK", shell("true"));
+        a.key.mode = Some(String::from("tv"));
+        let m = Mode::default();
+        let mut actions = Actions::new(vec![a], 4, m.clone());
+        assert_eq!(actions.start(&ev("1 0 KEY_OK d")).0, 0);
+        m.set("tv");
+        assert_eq!(actions.start(&ev("1 0 KEY_OK d")).0, 1);
+    }
+
+    #[test]
+    fn switch() {
+        let mut a = action("KEY_RED", shell("true"));
+        a.key.mode = Some(String::from(mode::DEFAULT_MODE));
+        a.switch = Some(String::from("tv"));
+        let mut b = action("KEY_RED", shell("true"));
+        b.key.mode = Some(String::from("tv"));
+        let m = Mode::default();
+        let mut actions = Actions::new(vec![a, b], 4, m.clone());
+        // the command runs in the mode the key was pressed in
+        assert_eq!(
+            actions.start(&ev("1 0 KEY_RED d")),
+            (1, Some(mode::announce("tv")))
+        );
+        assert_eq!(m.get(), "tv");
+        assert_eq!(actions.start(&ev("1 0 KEY_RED d")), (1, None));
+        wait_all(&actions);
+    }
+
+    fn wait_all(actions: &Actions) {
+        let end = Instant::now() + Duration::from_secs(5);
+        while actions.running() > 0 && Instant::now() < end {
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let dir = std::env::temp_dir().join(format!("changer-flags-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("log");
+        let cmd = |s: &str| shell(&format!("echo {} >> {}", s, log.display()));
+        let mut toggle = action("KEY_OK", cmd("on"));
+        toggle.commands.push(cmd("off"));
+        toggle.quit = true;
+        let mut once = action("KEY_UP", cmd("once"));
+        once.once = true;
+        let mut actions = Actions::new(
+            vec![toggle, action("KEY_OK", cmd("never")), once],
+            1,
+            Mode::default(),
+        );
+        for e in ["KEY_OK", "KEY_OK", "KEY_OK", "KEY_UP", "KEY_UP"] {
+            actions.start(&ev(&format!("1 0 {} d", e)));
+            wait_all(&actions);
+        }
+        let got = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(got, "on\noff\non\nonce\n");
+    }
+
+    #[test]
+    fn limit() {
+        let mut actions = Actions::new(
+            vec![
+                action("KEY_OK", shell("sleep 0.3")),
+                action("KEY_OK", shell("sleep 0.3")),
+            ],
+            1,
+            Mode::default(),
+        );
+        assert_eq!(actions.start(&ev("1 0 KEY_UP d")).0, 0);
+        assert_eq!(actions.start(&ev("1 0 KEY_OK d")).0, 1);
+        assert_eq!(actions.running(), 1);
+        assert_eq!(actions.start(&ev("1 0 KEY_OK d")).0, 0);
+        wait_all(&actions);
+        assert_eq!(actions.running(), 0);
+    }
+}
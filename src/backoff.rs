@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+/// Exponential backoff for reconnects: `min`, `min * 2`, ... up to `max`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    fails: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Backoff { min, max, fails: 0 }
+    }
+
+    pub fn fails(&self) -> u32 {
+        self.fails
+    }
+
+    pub fn wait(&mut self) -> Duration {
+        let res = self
+            .min
+            .checked_mul(2u32.saturating_pow(self.fails))
+            .map_or(self.max, |d| d.min(self.max));
+        self.fails = self.fails.saturating_add(1);
+        res
+    }
+
+    pub fn reset(&mut self) {
+        self.fails = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backoff::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn next() {
+        let mut b = Backoff::new(Duration::from_millis(500), Duration::from_millis(3000));
+        let got: Vec<u128> = (0..5).map(|_| b.wait().as_millis()).collect();
+        assert_eq!(got, vec![500, 1000, 2000, 3000, 3000]);
+        assert_eq!(b.fails(), 5);
+        for _ in 0..100 {
+            b.wait();
+        }
+        assert_eq!(b.wait(), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn reset() {
+        let mut b = Backoff::new(Duration::from_millis(100), Duration::from_millis(3000));
+        b.wait();
+        b.wait();
+        b.reset();
+        assert_eq!(b.fails(), 0);
+        assert_eq!(b.wait(), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,119 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Network like `192.168.1.0/24` or `fd00::/8`, a bare address is one host
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Tells if `ip` is in the network. IPv4 addresses mapped to IPv6, as
+    /// a dual stack listener reports them, match IPv4 networks.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(
+                u128::from(u32::from(net)) << 96,
+                u128::from(u32::from(ip)) << 96,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_eq(a: u128, b: u128, prefix: u8) -> bool {
+    match prefix {
+        0 => true,
+        p => (a ^ b) >> (128 - u32::from(p)) == 0,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (a, p) = match s.split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s, None),
+        };
+        let addr = a
+            .parse::<IpAddr>()
+            .map_err(|e| format!("wrong address '{}': {}", a, e))?;
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match p {
+            Some(p) => match p.parse::<u8>() {
+                Ok(p) if p <= max => p,
+                _ => return Err(format!("wrong prefix length '{}'", p)),
+            },
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cidr::Cidr;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Cidr::from_str("192.168.1.0/24").unwrap().to_string(),
+            "192.168.1.0/24"
+        );
+        assert_eq!(Cidr::from_str("::1").unwrap().to_string(), "::1/128");
+        assert_eq!(
+            Cidr::from_str("10.0.0.1").unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("10.0.0.0/x").is_err());
+        assert!(Cidr::from_str("host/8").is_err());
+    }
+
+    #[test]
+    fn v4() {
+        let c = Cidr::from_str("192.168.1.0/24").unwrap();
+        assert!(c.contains(&ip("192.168.1.1")));
+        assert!(c.contains(&ip("192.168.1.255")));
+        assert!(!c.contains(&ip("192.168.2.1")));
+        assert!(c.contains(&ip("::ffff:192.168.1.7")));
+        assert!(!c.contains(&ip("fe80::1")));
+        assert!(Cidr::from_str("0.0.0.0/0")
+            .unwrap()
+            .contains(&ip("8.8.8.8")));
+        let host = Cidr::from_str("10.0.0.1").unwrap();
+        assert!(host.contains(&ip("10.0.0.1")));
+        assert!(!host.contains(&ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn v6() {
+        let c = Cidr::from_str("fd00::/8").unwrap();
+        assert!(c.contains(&ip("fd12:3456::1")));
+        assert!(!c.contains(&ip("fe80::1")));
+        assert!(!c.contains(&ip("10.0.0.1")));
+        assert!(Cidr::from_str("::/0").unwrap().contains(&ip("::1")));
+    }
+}
@@ -0,0 +1,1766 @@
+use crate::action::{Action, Command};
+use crate::backoff::Backoff;
+use crate::cidr::Cidr;
+use crate::eventserver::{self, EventServerOutput};
+use crate::keymatch::{self, KeyMatch};
+use crate::kodi::{self, Endpoint};
+use crate::lircrc;
+use crate::mode::{self, ModeSwitch};
+use crate::remap::Remap;
+use crate::sequence::Sequence;
+use crate::server::TcpOutput;
+use crate::uinput::{self, KeyMap, UinputOutput};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+pub const DEFAULT_HOLD_MS: u64 = 500;
+pub const DEFAULT_TICK_MS: u64 = 100;
+pub const DEFAULT_IDLE_MS: u64 = 1000;
+pub const DEFAULT_HOLD_SUFFIX: &str = "_HOLD";
+pub const DEFAULT_TAP_MS: u64 = 300;
+pub const MAX_TAPS: u32 = 3;
+/// clients of a loopback TCP listener without `allow`
+const LOOPBACK_CLIENTS: [&str; 2] = ["127.0.0.0/8", "::1"];
+pub const DEFAULT_SEQUENCE_MS: u64 = 300;
+pub const DEFAULT_RECONNECT_MIN_MS: u64 = 500;
+pub const DEFAULT_RECONNECT_MAX_MS: u64 = 5000;
+pub const DEFAULT_MAX_ACTIONS: usize = 4;
+pub const DEFAULT_ACTION_TIMEOUT_MS: u64 = 10000;
+pub const DEFAULT_LIRCRC_PROG: &str = "irexec";
+/// lircrc flags that are read but change nothing
+const IGNORED_LIRCRC_FLAGS: [&str; 2] = ["mode", "toggle_reset"];
+pub const DEFAULT_KODI_TIMEOUT_MS: u64 = 2000;
+pub const DEFAULT_KODI_RETRIES: u32 = 2;
+pub const DEFAULT_KODI_RETRY_MS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct HoldConfig {
+    pub hold: Duration,
+    pub tick: Duration,
+    pub idle: Duration,
+}
+
+impl Default for HoldConfig {
+    fn default() -> Self {
+        HoldConfig {
+            hold: Duration::from_millis(DEFAULT_HOLD_MS),
+            tick: Duration::from_millis(DEFAULT_TICK_MS),
+            idle: Duration::from_millis(DEFAULT_IDLE_MS),
+        }
+    }
+}
+
+impl HoldConfig {
+    pub fn new(hold_ms: u64, tick_ms: u64, idle_ms: u64) -> Result<Self, String> {
+        if hold_ms == 0 {
+            return Err(String::from("hold_ms must be > 0"));
+        }
+        if tick_ms == 0 {
+            return Err(String::from("tick_ms must be > 0"));
+        }
+        if idle_ms == 0 {
+            return Err(String::from("idle_ms must be > 0"));
+        }
+        // a press without repeats is flushed after one tick, so a tick
+        // not shorter than the threshold would turn every press into HOLD
+        if tick_ms >= hold_ms {
+            return Err(format!(
+                "tick_ms ({}) must be less than hold_ms ({})",
+                tick_ms, hold_ms
+            ));
+        }
+        Ok(HoldConfig {
+            hold: Duration::from_millis(hold_ms),
+            tick: Duration::from_millis(tick_ms),
+            idle: Duration::from_millis(idle_ms),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HoldStage {
+    pub after: Duration,
+    pub suffix: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HoldRule {
+    pub enabled: bool,
+    /// ordered by `after`, never empty
+    pub stages: Vec<HoldStage>,
+    /// max short presses coalesced into one event, 1 - no tap detection
+    pub taps: u32,
+    /// max gap between short presses of the same key
+    pub tap: Duration,
+}
+
+impl HoldRule {
+    /// Returns the deepest stage reached by a press held for `held`.
+    pub fn stage(&self, held: Duration) -> Option<&HoldStage> {
+        self.stages.iter().rev().find(|s| held > s.after)
+    }
+
+    pub fn last(&self) -> &HoldStage {
+        self.stages.last().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct DeviceRule {
+    key: KeyMatch,
+    rule: HoldRule,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HoldRules {
+    default: HoldRule,
+    rules: Vec<DeviceRule>,
+}
+
+impl HoldRules {
+    pub fn new(cfg: &HoldConfig) -> Self {
+        HoldRules {
+            default: HoldRule {
+                enabled: true,
+                stages: vec![HoldStage {
+                    after: cfg.hold,
+                    suffix: String::from(DEFAULT_HOLD_SUFFIX),
+                }],
+                taps: 1,
+                tap: Duration::from_millis(DEFAULT_TAP_MS),
+            },
+            rules: vec![],
+        }
+    }
+
+    /// Returns the most specific rule for the key in `mode`, the default
+    /// one if none matches
+    pub fn find(&self, mode: &str, device: &str, name: &str) -> &HoldRule {
+        keymatch::find(&self.rules, |r| &r.key, mode, device, name)
+            .map_or(&self.default, |r| &r.rule)
+    }
+}
+
+/// Pipeline rules, replaced as a whole on reload
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Settings {
+    pub hold: HoldConfig,
+    pub rules: HoldRules,
+    pub sequences: Vec<Sequence>,
+    pub remaps: Vec<Remap>,
+    pub actions: Vec<Action>,
+    /// max actions running at once
+    pub max_actions: usize,
+    pub modes: Vec<ModeSwitch>,
+    /// mode of a lircrc `startup_mode` entry, set at start only
+    pub startup_mode: Option<String>,
+    /// keys typed on the uinput keyboard
+    pub keymap: KeyMap,
+    pub kodi_calls: Vec<kodi::Call>,
+}
+
+/// Settings read by every pipeline stage, all of them see a reload at once
+#[derive(Debug, Clone)]
+pub struct SharedSettings(Arc<RwLock<Arc<Settings>>>);
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        SharedSettings(Arc::new(RwLock::new(Arc::new(settings))))
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, settings: Settings) {
+        *self.0.write().unwrap() = Arc::new(settings);
+    }
+
+    /// Reader of one stage, made before the stage from its settings
+    pub fn reader(&self) -> SettingsReader {
+        SettingsReader {
+            shared: self.clone(),
+            seen: self.get(),
+        }
+    }
+}
+
+/// Settings a stage works with, tells when they are replaced
+#[derive(Debug, Clone)]
+pub struct SettingsReader {
+    shared: SharedSettings,
+    seen: Arc<Settings>,
+}
+
+impl SettingsReader {
+    pub fn get(&self) -> &Settings {
+        &self.seen
+    }
+
+    /// Returns the new settings if they were replaced since the last call
+    pub fn changed(&mut self) -> Option<Arc<Settings>> {
+        let s = self.shared.get();
+        if Arc::ptr_eq(&s, &self.seen) {
+            return None;
+        }
+        self.seen = s.clone();
+        Some(s)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub mode: Option<String>,
+    pub device: Option<String>,
+    pub name: Option<String>,
+    pub hold: Option<bool>,
+    pub hold_ms: Option<u64>,
+    pub suffix: Option<String>,
+    pub stages_ms: Option<Vec<u64>>,
+    pub suffixes: Option<Vec<String>>,
+    pub taps: Option<u32>,
+    pub tap_ms: Option<u64>,
+}
+
+impl RuleConfig {
+    fn taps(&self, cfg: &HoldConfig) -> Result<(u32, Duration), String> {
+        let taps = self.taps.unwrap_or(1);
+        if taps == 0 || taps > MAX_TAPS {
+            return Err(format!("taps ({}) must be in [1, {}]", taps, MAX_TAPS));
+        }
+        let tap = Duration::from_millis(self.tap_ms.unwrap_or(DEFAULT_TAP_MS));
+        if tap <= cfg.tick {
+            return Err(format!(
+                "tap_ms ({}) must be greater than tick_ms ({})",
+                tap.as_millis(),
+                cfg.tick.as_millis()
+            ));
+        }
+        Ok((taps, tap))
+    }
+
+    /// Stage suffixes default to `suffix`, `suffix2`, `suffix3`...
+    fn stages(&self, cfg: &HoldConfig) -> Result<Vec<HoldStage>, String> {
+        let times = match (&self.hold_ms, &self.stages_ms) {
+            (Some(_), Some(_)) => {
+                return Err(String::from("hold_ms and stages_ms can't be used together"))
+            }
+            (Some(ms), None) => vec![Duration::from_millis(*ms)],
+            (None, Some(v)) => v.iter().map(|ms| Duration::from_millis(*ms)).collect(),
+            (None, None) => vec![cfg.hold],
+        };
+        if times.is_empty() {
+            return Err(String::from("stages_ms can't be empty"));
+        }
+        if times[0] <= cfg.tick {
+            return Err(format!(
+                "hold_ms ({}) must be greater than tick_ms ({})",
+                times[0].as_millis(),
+                cfg.tick.as_millis()
+            ));
+        }
+        if times.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(String::from("stages_ms must be increasing"));
+        }
+        let suffixes = match &self.suffixes {
+            Some(v) => {
+                if v.len() != times.len() {
+                    return Err(format!(
+                        "suffixes count ({}) must match stages count ({})",
+                        v.len(),
+                        times.len()
+                    ));
+                }
+                v.clone()
+            }
+            None => {
+                let suffix = self.suffix.as_deref().unwrap_or(DEFAULT_HOLD_SUFFIX);
+                (0..times.len())
+                    .map(|i| match i {
+                        0 => suffix.to_string(),
+                        _ => format!("{}{}", suffix, i + 1),
+                    })
+                    .collect()
+            }
+        };
+        if suffixes.iter().any(|s| s.is_empty()) {
+            return Err(String::from("suffix can't be empty"));
+        }
+        Ok(times
+            .into_iter()
+            .zip(suffixes)
+            .map(|(after, suffix)| HoldStage { after, suffix })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SequenceConfig {
+    pub keys: Vec<String>,
+    pub device: Option<String>,
+    pub within_ms: Option<u64>,
+    pub emit: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RemapConfig {
+    pub mode: Option<String>,
+    pub device: Option<String>,
+    pub name: Option<String>,
+    pub to: Option<String>,
+    pub to_device: Option<String>,
+    pub drop: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ActionConfig {
+    pub mode: Option<String>,
+    pub name: String,
+    pub device: Option<String>,
+    pub repeat: Option<bool>,
+    pub shell: Option<String>,
+    pub argv: Option<Vec<String>>,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ModeConfig {
+    pub mode: String,
+    pub key: String,
+    pub device: Option<String>,
+    /// the key switches only in this mode
+    pub in_mode: Option<String>,
+    /// the key switches back to the default mode if its mode is active
+    pub toggle: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TcpConfig {
+    /// `host:port`, `[::]:port` for IPv6
+    pub address: String,
+    /// client networks like `192.168.1.0/24`, no one if empty. Needed
+    /// unless `address` is a loopback one, then loopback clients are allowed.
+    pub allow: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KodiCallConfig {
+    pub mode: Option<String>,
+    pub name: String,
+    pub device: Option<String>,
+    pub repeat: Option<bool>,
+    pub method: String,
+    pub params: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KodiConfig {
+    /// `http://host:port/jsonrpc` or `tcp://host:9090`
+    pub url: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+    pub retry_ms: Option<u64>,
+    #[serde(default)]
+    pub calls: Vec<KodiCallConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EventServerConfig {
+    /// `host:port`, Kodi on this machine by default
+    pub address: Option<String>,
+    pub name: Option<String>,
+    /// button map, `LI:<device>` of Lircmap.xml by default
+    pub map: Option<String>,
+    pub release_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UinputConfig {
+    pub path: Option<String>,
+    pub name: Option<String>,
+    /// event names to key names
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>,
+    /// key held while typing unmapped variants of keys, like `KEY_OK_HOLD`
+    pub modifier: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub hold_ms: Option<u64>,
+    pub tick_ms: Option<u64>,
+    pub idle_ms: Option<u64>,
+    pub reconnect_min_ms: Option<u64>,
+    pub reconnect_max_ms: Option<u64>,
+    pub read_timeout_ms: Option<u64>,
+    pub max_actions: Option<usize>,
+    pub lircrc: Option<String>,
+    /// lircrc entries of this `prog` are run as actions
+    pub lircrc_prog: Option<String>,
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    #[serde(default)]
+    pub sequences: Vec<SequenceConfig>,
+    #[serde(default)]
+    pub remap: Vec<RemapConfig>,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+    #[serde(default)]
+    pub modes: Vec<ModeConfig>,
+    #[serde(default)]
+    pub tcp: Vec<TcpConfig>,
+    pub uinput: Option<UinputConfig>,
+    pub kodi: Option<KodiConfig>,
+    pub eventserver: Option<EventServerConfig>,
+}
+
+impl FromStr for FileConfig {
+    type Err = String;
+
+    fn from_str(data: &str) -> Result<Self, Self::Err> {
+        toml::from_str(data).map_err(|e| format!("can't parse config: {}", e))
+    }
+}
+
+impl FileConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| format!("can't read config '{}': {}", path, e))?;
+        FileConfig::from_str(&data)
+    }
+
+    pub fn hold_config(
+        &self,
+        hold_ms: Option<u64>,
+        tick_ms: Option<u64>,
+        idle_ms: Option<u64>,
+    ) -> Result<HoldConfig, String> {
+        HoldConfig::new(
+            hold_ms.or(self.hold_ms).unwrap_or(DEFAULT_HOLD_MS),
+            tick_ms.or(self.tick_ms).unwrap_or(DEFAULT_TICK_MS),
+            idle_ms.or(self.idle_ms).unwrap_or(DEFAULT_IDLE_MS),
+        )
+    }
+
+    pub fn settings(&self, hold: HoldConfig) -> Result<Settings, String> {
+        let entries = match &self.lircrc {
+            Some(path) => lircrc::load(path)?,
+            None => vec![],
+        };
+        for e in self.lircrc_entries(&entries) {
+            for f in e
+                .flags
+                .iter()
+                .filter(|f| IGNORED_LIRCRC_FLAGS.contains(&f.as_str()))
+            {
+                log::warn!(
+                    "lircrc line {}: flag '{}' is not supported, ignored",
+                    e.line,
+                    f
+                );
+            }
+        }
+        Ok(Settings {
+            hold,
+            rules: self.hold_rules(&hold)?,
+            sequences: self.sequences(&entries)?,
+            remaps: self.remaps()?,
+            actions: self.actions(&entries)?,
+            max_actions: self.max_actions()?,
+            modes: self.modes(&entries)?,
+            startup_mode: self.startup_mode(&entries)?,
+            keymap: self.keymap()?,
+            kodi_calls: self.kodi_calls()?,
+        })
+    }
+
+    pub fn backoff(&self, min_ms: Option<u64>, max_ms: Option<u64>) -> Result<Backoff, String> {
+        let min = min_ms
+            .or(self.reconnect_min_ms)
+            .unwrap_or(DEFAULT_RECONNECT_MIN_MS);
+        let max = max_ms
+            .or(self.reconnect_max_ms)
+            .unwrap_or(DEFAULT_RECONNECT_MAX_MS);
+        if min == 0 {
+            return Err(String::from("reconnect_min_ms must be > 0"));
+        }
+        if max < min {
+            return Err(format!(
+                "reconnect_max_ms ({}) must be >= reconnect_min_ms ({})",
+                max, min
+            ));
+        }
+        Ok(Backoff::new(
+            Duration::from_millis(min),
+            Duration::from_millis(max),
+        ))
+    }
+
+    /// Input read timeout, `None` waits for events forever
+    pub fn read_timeout(&self, read_timeout_ms: Option<u64>) -> Result<Option<Duration>, String> {
+        match read_timeout_ms.or(self.read_timeout_ms) {
+            Some(0) => Err(String::from("read_timeout_ms must be > 0")),
+            ms => Ok(ms.map(Duration::from_millis)),
+        }
+    }
+
+    pub fn hold_rules(&self, cfg: &HoldConfig) -> Result<HoldRules, String> {
+        let mut res = HoldRules::new(cfg);
+        for (i, rc) in self.rules.iter().enumerate() {
+            if rc.device.is_none() && rc.name.is_none() {
+                return Err(format!("rule {}: device or name must be set", i + 1));
+            }
+            let stages = rc
+                .stages(cfg)
+                .map_err(|e| format!("rule {}: {}", i + 1, e))?;
+            let (taps, tap) = rc.taps(cfg).map_err(|e| format!("rule {}: {}", i + 1, e))?;
+            check_mode(&rc.mode).map_err(|e| format!("rule {}: {}", i + 1, e))?;
+            res.rules.push(DeviceRule {
+                key: KeyMatch {
+                    mode: rc.mode.clone(),
+                    device: rc.device.clone(),
+                    name: rc.name.clone(),
+                    ignore_case: false,
+                },
+                rule: HoldRule {
+                    enabled: rc.hold.unwrap_or(true),
+                    stages,
+                    taps,
+                    tap,
+                },
+            });
+        }
+        Ok(res)
+    }
+
+    /// Sequences of the file and of lircrc `entries` with several buttons
+    pub fn sequences(&self, entries: &[lircrc::Entry]) -> Result<Vec<Sequence>, String> {
+        let mut res = vec![];
+        for (i, sc) in self.sequences.iter().enumerate() {
+            if sc.keys.len() < 2 {
+                return Err(format!("sequence {}: at least 2 keys required", i + 1));
+            }
+            let within = sc.within_ms.unwrap_or(DEFAULT_SEQUENCE_MS);
+            if within == 0 {
+                return Err(format!("sequence {}: within_ms must be > 0", i + 1));
+            }
+            let emit = sc.emit.clone().unwrap_or_else(|| sc.keys.join("+"));
+            if emit.is_empty() {
+                return Err(format!("sequence {}: emit can't be empty", i + 1));
+            }
+            res.push(Sequence {
+                keys: sc.keys.clone(),
+                device: sc.device.clone(),
+                within: Duration::from_millis(within),
+                emit,
+                ignore_case: false,
+            });
+        }
+        for e in self.lircrc_entries(entries) {
+            let keys = match lircrc_sequence(e) {
+                Some(keys) => keys,
+                None => continue,
+            };
+            let s = Sequence {
+                emit: keys.join("+"),
+                keys,
+                device: e.remote.clone(),
+                within: Duration::from_millis(DEFAULT_SEQUENCE_MS),
+                ignore_case: true,
+            };
+            if !res.contains(&s) {
+                res.push(s);
+            }
+        }
+        Ok(res)
+    }
+
+    pub fn remaps(&self) -> Result<Vec<Remap>, String> {
+        let mut res = vec![];
+        for (i, rc) in self.remap.iter().enumerate() {
+            if rc.device.is_none() && rc.name.is_none() {
+                return Err(format!("remap {}: device or name must be set", i + 1));
+            }
+            let drop = rc.drop.unwrap_or(false);
+            if drop && (rc.to.is_some() || rc.to_device.is_some()) {
+                return Err(format!(
+                    "remap {}: drop can't be used with to or to_device",
+                    i + 1
+                ));
+            }
+            if !drop && rc.to.is_none() && rc.to_device.is_none() {
+                return Err(format!(
+                    "remap {}: to, to_device or drop must be set",
+                    i + 1
+                ));
+            }
+            // the new values go to the lircd line as separate fields
+            for v in [&rc.to, &rc.to_device].into_iter().flatten() {
+                if v.is_empty() || v.contains(char::is_whitespace) {
+                    return Err(format!("remap {}: wrong name '{}'", i + 1, v));
+                }
+            }
+            check_mode(&rc.mode).map_err(|e| format!("remap {}: {}", i + 1, e))?;
+            res.push(Remap {
+                key: KeyMatch {
+                    mode: rc.mode.clone(),
+                    device: rc.device.clone(),
+                    name: rc.name.clone(),
+                    ignore_case: false,
+                },
+                to_name: rc.to.clone(),
+                to_device: rc.to_device.clone(),
+                drop,
+            });
+        }
+        Ok(res)
+    }
+
+    /// Actions of the file and of lircrc `entries`
+    pub fn actions(&self, entries: &[lircrc::Entry]) -> Result<Vec<Action>, String> {
+        let mut res = vec![];
+        for (i, ac) in self.actions.iter().enumerate() {
+            if ac.name.is_empty() {
+                return Err(format!("action {}: name can't be empty", i + 1));
+            }
+            let command = match (&ac.shell, &ac.argv) {
+                (Some(s), None) if !s.trim().is_empty() => Command::Shell(s.clone()),
+                (None, Some(v)) if v.first().is_some_and(|c| !c.is_empty()) => {
+                    Command::Argv(v.clone())
+                }
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "action {}: shell and argv can't be used together",
+                        i + 1
+                    ))
+                }
+                (None, None) => return Err(format!("action {}: shell or argv must be set", i + 1)),
+                _ => return Err(format!("action {}: command can't be empty", i + 1)),
+            };
+            let timeout = ac.timeout_ms.unwrap_or(DEFAULT_ACTION_TIMEOUT_MS);
+            if timeout == 0 {
+                return Err(format!("action {}: timeout_ms must be > 0", i + 1));
+            }
+            check_mode(&ac.mode).map_err(|e| format!("action {}: {}", i + 1, e))?;
+            res.push(Action {
+                key: KeyMatch {
+                    mode: ac.mode.clone(),
+                    device: ac.device.clone(),
+                    name: Some(ac.name.clone()),
+                    ignore_case: false,
+                },
+                repeat: u32::from(ac.repeat.unwrap_or(false)),
+                delay: 0,
+                commands: vec![command],
+                timeout: Duration::from_millis(timeout),
+                quit: false,
+                once: false,
+                switch: None,
+            });
+        }
+        res.extend(self.lircrc_actions(entries)?);
+        Ok(res)
+    }
+
+    /// Makes actions of `lircrc_prog` entries with `config` commands, a
+    /// button sequence runs them on the event of its sequence. An entry with
+    /// `mode` too enters it after starting the command, as irexec does.
+    pub fn lircrc_actions(&self, entries: &[lircrc::Entry]) -> Result<Vec<Action>, String> {
+        let mut res = vec![];
+        for e in self.lircrc_entries(entries) {
+            let name = match (e.buttons.as_slice(), lircrc_sequence(e)) {
+                ([], _) => continue,
+                (_, Some(keys)) => Some(keys.join("+")),
+                ([b, ..], None) => b.clone(),
+            };
+            let commands = lircrc_commands(e);
+            if commands.is_empty() {
+                continue;
+            }
+            if let Some(m) = &e.mode {
+                mode::check_name(m).map_err(|err| format!("lircrc line {}: {}", e.line, err))?;
+            }
+            res.push(Action {
+                key: KeyMatch {
+                    mode: e.in_mode.clone(),
+                    device: e.remote.clone(),
+                    name,
+                    ignore_case: true,
+                },
+                repeat: e.repeat,
+                delay: e.delay,
+                commands,
+                timeout: Duration::from_millis(DEFAULT_ACTION_TIMEOUT_MS),
+                quit: e.has_flag(lircrc::QUIT),
+                once: e.has_flag(lircrc::ONCE),
+                switch: e.mode.clone(),
+            });
+        }
+        Ok(res)
+    }
+
+    fn lircrc_entries<'a>(
+        &self,
+        entries: &'a [lircrc::Entry],
+    ) -> impl Iterator<Item = &'a lircrc::Entry> {
+        let prog = self
+            .lircrc_prog
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_LIRCRC_PROG));
+        entries.iter().filter(move |e| e.prog == prog)
+    }
+
+    /// Mode switches of the file and of lircrc `entries` with `mode` and
+    /// no `config`, the actions of the others switch
+    pub fn modes(&self, entries: &[lircrc::Entry]) -> Result<Vec<ModeSwitch>, String> {
+        let mut res = vec![];
+        for (i, mc) in self.modes.iter().enumerate() {
+            mode::check_name(&mc.mode)
+                .and_then(|_| check_mode(&mc.in_mode))
+                .map_err(|e| format!("mode {}: {}", i + 1, e))?;
+            if mc.key.is_empty() {
+                return Err(format!("mode {}: key can't be empty", i + 1));
+            }
+            res.push(ModeSwitch {
+                key: KeyMatch {
+                    mode: mc.in_mode.clone(),
+                    device: mc.device.clone(),
+                    name: Some(mc.key.clone()),
+                    ignore_case: false,
+                },
+                mode: mc.mode.clone(),
+                toggle: mc.toggle.unwrap_or(false),
+            });
+        }
+        for e in self.lircrc_entries(entries) {
+            if !lircrc_commands(e).is_empty() {
+                continue;
+            }
+            let (m, key) = match (&e.mode, e.buttons.as_slice()) {
+                (Some(_), []) | (None, _) => continue,
+                (Some(m), [Some(key)]) => (m, key),
+                (Some(_), [None]) => {
+                    log::warn!("lircrc line {}: mode switch on any button, skipped", e.line);
+                    continue;
+                }
+                (Some(_), _) => {
+                    log::warn!("lircrc line {}: mode switch on a sequence, skipped", e.line);
+                    continue;
+                }
+            };
+            mode::check_name(m).map_err(|err| format!("lircrc line {}: {}", e.line, err))?;
+            res.push(ModeSwitch {
+                key: KeyMatch {
+                    mode: e.in_mode.clone(),
+                    device: e.remote.clone(),
+                    name: Some(key.clone()),
+                    ignore_case: true,
+                },
+                mode: m.clone(),
+                toggle: false,
+            });
+        }
+        Ok(res)
+    }
+
+    /// Mode of the last lircrc entry with the `startup_mode` flag
+    pub fn startup_mode(&self, entries: &[lircrc::Entry]) -> Result<Option<String>, String> {
+        let e = match self
+            .lircrc_entries(entries)
+            .filter(|e| e.has_flag(lircrc::STARTUP_MODE))
+            .last()
+        {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let m = e.mode.clone().unwrap_or_default();
+        mode::check_name(&m).map_err(|err| format!("lircrc line {}: {}", e.line, err))?;
+        Ok(Some(m))
+    }
+
+    /// Names of the settings used at start only that differ from `start`
+    pub fn fixed_changes(&self, start: &FileConfig) -> Vec<&'static str> {
+        let uinput = |fc: &FileConfig| fc.uinput.as_ref().map(|u| (u.path.clone(), u.name.clone()));
+        // calls are reloaded, the client is not
+        let kodi = |fc: &FileConfig| {
+            fc.kodi.as_ref().map(|k| KodiConfig {
+                calls: vec![],
+                ..k.clone()
+            })
+        };
+        [
+            (
+                "reconnect_min_ms",
+                self.reconnect_min_ms != start.reconnect_min_ms,
+            ),
+            (
+                "reconnect_max_ms",
+                self.reconnect_max_ms != start.reconnect_max_ms,
+            ),
+            (
+                "read_timeout_ms",
+                self.read_timeout_ms != start.read_timeout_ms,
+            ),
+            ("[[tcp]]", self.tcp != start.tcp),
+            ("[uinput]", uinput(self) != uinput(start)),
+            ("[kodi]", kodi(self) != kodi(start)),
+            ("[eventserver]", self.eventserver != start.eventserver),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    pub fn max_actions(&self) -> Result<usize, String> {
+        match self.max_actions.unwrap_or(DEFAULT_MAX_ACTIONS) {
+            0 => Err(String::from("max_actions must be > 0")),
+            n => Ok(n),
+        }
+    }
+
+    /// TCP output listeners, bound at start only
+    pub fn tcp_outputs(&self) -> Result<Vec<TcpOutput>, String> {
+        let mut res = vec![];
+        for (i, tc) in self.tcp.iter().enumerate() {
+            let address: SocketAddr = tc
+                .address
+                .parse()
+                .map_err(|e| format!("tcp {}: wrong address '{}': {}", i + 1, tc.address, e))?;
+            let allow = match &tc.allow {
+                Some(allow) => allow
+                    .iter()
+                    .map(|c| Cidr::from_str(c))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("tcp {}: {}", i + 1, e))?,
+                None if address.ip().is_loopback() => LOOPBACK_CLIENTS
+                    .iter()
+                    .map(|c| Cidr::from_str(c).unwrap())
+                    .collect(),
+                None => {
+                    return Err(format!(
+                    "tcp {}: allow is needed on '{}', [\"0.0.0.0/0\", \"::/0\"] allows everyone",
+                    i + 1,
+                    tc.address
+                ))
+                }
+            };
+            res.push(TcpOutput { address, allow });
+        }
+        Ok(res)
+    }
+
+    /// Virtual keyboard, created at start only
+    pub fn uinput_output(&self) -> Option<UinputOutput> {
+        self.uinput.as_ref().map(|uc| UinputOutput {
+            path: uc
+                .path
+                .as_deref()
+                .unwrap_or(uinput::DEFAULT_PATH)
+                .to_string(),
+            name: uc
+                .name
+                .as_deref()
+                .unwrap_or(uinput::DEFAULT_NAME)
+                .to_string(),
+        })
+    }
+
+    pub fn keymap(&self) -> Result<KeyMap, String> {
+        match &self.uinput {
+            Some(uc) => {
+                let res = KeyMap::new(uc.keys.iter().map(|(n, k)| (n.as_str(), k.as_str())));
+                match &uc.modifier {
+                    Some(m) => res.and_then(|k| k.with_modifier(m)),
+                    None => res,
+                }
+                .map_err(|e| format!("uinput: {}", e))
+            }
+            None => Ok(KeyMap::default()),
+        }
+    }
+
+    /// Kodi EventServer output, set at start only
+    pub fn eventserver_output(&self) -> Result<Option<EventServerOutput>, String> {
+        let ec = match &self.eventserver {
+            Some(ec) => ec,
+            None => return Ok(None),
+        };
+        let address = ec
+            .address
+            .as_deref()
+            .unwrap_or(eventserver::DEFAULT_ADDRESS);
+        let address = eventserver::resolve(address).map_err(|e| format!("eventserver: {}", e))?;
+        if ec.map.as_ref().is_some_and(|m| m.is_empty()) {
+            return Err(String::from("eventserver: map can't be empty"));
+        }
+        let release = ec.release_ms.unwrap_or(eventserver::DEFAULT_RELEASE_MS);
+        if release == 0 {
+            return Err(String::from("eventserver: release_ms must be > 0"));
+        }
+        Ok(Some(EventServerOutput {
+            address,
+            name: ec
+                .name
+                .as_deref()
+                .unwrap_or(eventserver::DEFAULT_NAME)
+                .to_string(),
+            map: ec.map.clone(),
+            release: Duration::from_millis(release),
+        }))
+    }
+
+    /// Kodi JSON-RPC client, made at start only
+    pub fn kodi_client(&self) -> Result<Option<kodi::Client>, String> {
+        let kc = match &self.kodi {
+            Some(kc) => kc,
+            None => return Ok(None),
+        };
+        let endpoint = Endpoint::from_str(&kc.url).map_err(|e| format!("kodi: {}", e))?;
+        let timeout = kc.timeout_ms.unwrap_or(DEFAULT_KODI_TIMEOUT_MS);
+        if timeout == 0 {
+            return Err(String::from("kodi: timeout_ms must be > 0"));
+        }
+        let mut res = kodi::Client::new(endpoint, Duration::from_millis(timeout)).with_retries(
+            kc.retries.unwrap_or(DEFAULT_KODI_RETRIES),
+            Duration::from_millis(kc.retry_ms.unwrap_or(DEFAULT_KODI_RETRY_MS)),
+        );
+        match (&kc.user, &kc.password) {
+            (Some(u), p) => res = res.with_auth(u, p.as_deref().unwrap_or_default()),
+            (None, Some(_)) => return Err(String::from("kodi: password needs user")),
+            (None, None) => {}
+        }
+        Ok(Some(res))
+    }
+
+    pub fn kodi_calls(&self) -> Result<Vec<kodi::Call>, String> {
+        let calls = self.kodi.as_ref().map_or(&[][..], |kc| &kc.calls);
+        let mut res = vec![];
+        for (i, cc) in calls.iter().enumerate() {
+            if cc.name.is_empty() {
+                return Err(format!("kodi call {}: name can't be empty", i + 1));
+            }
+            if cc.method.is_empty() {
+                return Err(format!("kodi call {}: method can't be empty", i + 1));
+            }
+            let params = cc.params.clone().unwrap_or_default();
+            if !(params.is_null() || params.is_object() || params.is_array()) {
+                return Err(format!(
+                    "kodi call {}: params must be a table or an array",
+                    i + 1
+                ));
+            }
+            check_mode(&cc.mode).map_err(|e| format!("kodi call {}: {}", i + 1, e))?;
+            res.push(kodi::Call {
+                key: KeyMatch {
+                    mode: cc.mode.clone(),
+                    device: cc.device.clone(),
+                    name: Some(cc.name.clone()),
+                    ignore_case: false,
+                },
+                repeat: cc.repeat.unwrap_or(false),
+                method: cc.method.clone(),
+                params,
+            });
+        }
+        Ok(res)
+    }
+}
+
+/// Commands of the entry's `config` lines, empty ones are skipped
+fn lircrc_commands(e: &lircrc::Entry) -> Vec<Command> {
+    e.configs
+        .iter()
+        .filter(|c| !c.trim().is_empty())
+        .map(|c| Command::Shell(c.clone()))
+        .collect()
+}
+
+fn check_mode(m: &Option<String>) -> Result<(), String> {
+    match m {
+        Some(m) => mode::check_name(m),
+        None => Ok(()),
+    }
+}
+
+/// Keys of a lircrc entry with several buttons, `*` is not among them
+fn lircrc_sequence(e: &lircrc::Entry) -> Option<Vec<String>> {
+    (e.buttons.len() > 1).then(|| e.buttons.iter().flatten().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::action::Command;
+    use crate::backoff::Backoff;
+    use crate::config::{FileConfig, HoldConfig, HoldRule, HoldStage, SharedSettings};
+    use crate::keymatch::KeyMatch;
+    use crate::lircrc;
+    use crate::mode::{ModeSwitch, DEFAULT_MODE};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    #[test]
+    fn hold_config_new() {
+        assert_eq!(
+            HoldConfig::new(600, 80, 2000),
+            Ok(HoldConfig {
+                hold: Duration::from_millis(600),
+                tick: Duration::from_millis(80),
+                idle: Duration::from_millis(2000),
+            })
+        );
+    }
+
+    #[test]
+    fn hold_config_validate() {
+        assert!(HoldConfig::new(0, 100, 1000).is_err());
+        assert!(HoldConfig::new(500, 0, 1000).is_err());
+        assert!(HoldConfig::new(500, 100, 0).is_err());
+        assert_eq!(
+            HoldConfig::new(500, 500, 1000),
+            Err(String::from(
+                "tick_ms (500) must be less than hold_ms (500)"
+            ))
+        );
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!(FileConfig::from_str(""), Ok(FileConfig::default()));
+        assert_eq!(
+            FileConfig::from_str("hold_ms = 700\ntick_ms = 120"),
+            Ok(FileConfig {
+                hold_ms: Some(700),
+                tick_ms: Some(120),
+                idle_ms: None,
+                reconnect_min_ms: None,
+                reconnect_max_ms: None,
+                read_timeout_ms: None,
+                max_actions: None,
+                lircrc: None,
+                lircrc_prog: None,
+                rules: vec![],
+                sequences: vec![],
+                remap: vec![],
+                actions: vec![],
+                modes: vec![],
+                tcp: vec![],
+                uinput: None,
+                kodi: None,
+                eventserver: None,
+            })
+        );
+        assert!(FileConfig::from_str("hold = 700").is_err());
+        assert!(FileConfig::from_str("hold_ms = -1").is_err());
+    }
+
+    #[test]
+    fn hold_config_merge() {
+        let fc = FileConfig::from_str("hold_ms = 700\ntick_ms = 120").unwrap();
+        assert_eq!(
+            fc.hold_config(None, None, None),
+            HoldConfig::new(700, 120, 1000)
+        );
+        assert_eq!(
+            fc.hold_config(Some(900), None, Some(3000)),
+            HoldConfig::new(900, 120, 3000)
+        );
+        assert_eq!(
+            FileConfig::default().hold_config(None, None, None),
+            Ok(HoldConfig::default())
+        );
+    }
+
+    const RULES: &str = r#"
+[[rules]]
+name = "KEY_VOLUMEUP"
+hold = false
+
+[[rules]]
+device = "apple"
+hold_ms = 800
+
+[[rules]]
+device = "apple"
+name = "KEY_OK"
+suffix = "_LONG"
+"#;
+
+    fn rule(enabled: bool, ms: u64, suffix: &str) -> HoldRule {
+        HoldRule {
+            enabled,
+            stages: vec![HoldStage {
+                after: Duration::from_millis(ms),
+                suffix: String::from(suffix),
+            }],
+            taps: 1,
+            tap: Duration::from_millis(300),
+        }
+    }
+
+    #[test]
+    fn hold_rules_find() {
+        let fc = FileConfig::from_str(RULES).unwrap();
+        let rules = fc.hold_rules(&HoldConfig::default()).unwrap();
+        assert_eq!(
+            rules.find(DEFAULT_MODE, "nec", "KEY_UP"),
+            &rule(true, 500, "_HOLD")
+        );
+        assert_eq!(
+            rules.find(DEFAULT_MODE, "nec", "KEY_VOLUMEUP"),
+            &rule(false, 500, "_HOLD")
+        );
+        assert_eq!(
+            rules.find(DEFAULT_MODE, "apple", "KEY_VOLUMEUP"),
+            &rule(false, 500, "_HOLD")
+        );
+        assert_eq!(
+            rules.find(DEFAULT_MODE, "apple", "KEY_UP"),
+            &rule(true, 800, "_HOLD")
+        );
+        assert_eq!(
+            rules.find(DEFAULT_MODE, "apple", "KEY_OK"),
+            &rule(true, 500, "_LONG")
+        );
+    }
+
+    #[test]
+    fn hold_rules_validate() {
+        let cfg = HoldConfig::default();
+        let fc = FileConfig::from_str("[[rules]]\nhold = false").unwrap();
+        assert_eq!(
+            fc.hold_rules(&cfg),
+            Err(String::from("rule 1: device or name must be set"))
+        );
+        let fc = FileConfig::from_str("[[rules]]\nname = \"a\"\nhold_ms = 100").unwrap();
+        assert_eq!(
+            fc.hold_rules(&cfg),
+            Err(String::from(
+                "rule 1: hold_ms (100) must be greater than tick_ms (100)"
+            ))
+        );
+        let fc = FileConfig::from_str("[[rules]]\nname = \"a\"\nsuffix = \"\"").unwrap();
+        assert!(fc.hold_rules(&cfg).is_err());
+    }
+
+    #[test]
+    fn hold_rules_stages() {
+        let fc = FileConfig::from_str(
+            "[[rules]]\nname = \"KEY_OK\"\nstages_ms = [500, 1500, 3000]\n\
+            [[rules]]\nname = \"KEY_UP\"\nstages_ms = [300, 900]\nsuffixes = [\"_A\", \"_B\"]",
+        )
+        .unwrap();
+        let rules = fc.hold_rules(&HoldConfig::default()).unwrap();
+        let r = rules.find(DEFAULT_MODE, "d", "KEY_OK");
+        let suffixes: Vec<&str> = r.stages.iter().map(|s| s.suffix.as_str()).collect();
+        assert_eq!(suffixes, vec!["_HOLD", "_HOLD2", "_HOLD3"]);
+        assert_eq!(r.stage(Duration::from_millis(400)), None);
+        assert_eq!(r.stage(Duration::from_millis(501)).unwrap().suffix, "_HOLD");
+        assert_eq!(
+            r.stage(Duration::from_millis(1500)).unwrap().suffix,
+            "_HOLD"
+        );
+        assert_eq!(
+            r.stage(Duration::from_millis(2000)).unwrap().suffix,
+            "_HOLD2"
+        );
+        assert_eq!(
+            r.stage(Duration::from_millis(9000)).unwrap().suffix,
+            "_HOLD3"
+        );
+        assert_eq!(r.last().suffix, "_HOLD3");
+        let r = rules.find(DEFAULT_MODE, "d", "KEY_UP");
+        assert_eq!(r.stage(Duration::from_millis(1000)).unwrap().suffix, "_B");
+    }
+
+    #[test]
+    fn hold_rules_stages_validate() {
+        let cfg = HoldConfig::default();
+        for (data, err) in [
+            (
+                "stages_ms = [500, 400]",
+                "rule 1: stages_ms must be increasing",
+            ),
+            ("stages_ms = []", "rule 1: stages_ms can't be empty"),
+            (
+                "stages_ms = [500]\nhold_ms = 500",
+                "rule 1: hold_ms and stages_ms can't be used together",
+            ),
+            (
+                "stages_ms = [500, 900]\nsuffixes = [\"_A\"]",
+                "rule 1: suffixes count (1) must match stages count (2)",
+            ),
+        ] {
+            let fc = FileConfig::from_str(&format!("[[rules]]\nname = \"a\"\n{}", data)).unwrap();
+            assert_eq!(fc.hold_rules(&cfg), Err(String::from(err)));
+        }
+    }
+
+    #[test]
+    fn hold_rules_taps() {
+        let cfg = HoldConfig::default();
+        let fc = FileConfig::from_str("[[rules]]\nname = \"a\"\ntaps = 3\ntap_ms = 400").unwrap();
+        let rules = fc.hold_rules(&cfg).unwrap();
+        assert_eq!(rules.find(DEFAULT_MODE, "d", "a").taps, 3);
+        assert_eq!(
+            rules.find(DEFAULT_MODE, "d", "a").tap,
+            Duration::from_millis(400)
+        );
+        assert_eq!(rules.find(DEFAULT_MODE, "d", "b").taps, 1);
+        let fc = FileConfig::from_str("[[rules]]\nname = \"a\"\ntaps = 4").unwrap();
+        assert_eq!(
+            fc.hold_rules(&cfg),
+            Err(String::from("rule 1: taps (4) must be in [1, 3]"))
+        );
+        let fc = FileConfig::from_str("[[rules]]\nname = \"a\"\ntaps = 2\ntap_ms = 50").unwrap();
+        assert_eq!(
+            fc.hold_rules(&cfg),
+            Err(String::from(
+                "rule 1: tap_ms (50) must be greater than tick_ms (100)"
+            ))
+        );
+    }
+
+    #[test]
+    fn sequences() {
+        let fc = FileConfig::from_str(
+            "[[sequences]]\nkeys = [\"MENU\", \"PLAY\"]\n\
+            [[sequences]]\nkeys = [\"A\", \"B\"]\nwithin_ms = 500\nemit = \"AB\"\ndevice = \"d\"",
+        )
+        .unwrap();
+        let seqs = fc.sequences(&[]).unwrap();
+        assert_eq!(seqs.len(), 2);
+        assert_eq!(seqs[0].emit, "MENU+PLAY");
+        assert_eq!(seqs[0].within, Duration::from_millis(300));
+        assert_eq!(seqs[1].emit, "AB");
+        assert_eq!(seqs[1].device, Some(String::from("d")));
+        let fc = FileConfig::from_str("[[sequences]]\nkeys = [\"MENU\"]").unwrap();
+        assert_eq!(
+            fc.sequences(&[]),
+            Err(String::from("sequence 1: at least 2 keys required"))
+        );
+    }
+
+    #[test]
+    fn remaps() {
+        let fc = FileConfig::from_str(
+            "[[remap]]\nname = \"KEY_MENU\"\nto = \"KEY_CONTEXT_MENU\"\n\
+            [[remap]]\ndevice = \"mce\"\nto_device = \"remote\"\n\
+            [[remap]]\nname = \"KEY_POWER\"\ndrop = true",
+        )
+        .unwrap();
+        let remaps = fc.remaps().unwrap();
+        assert_eq!(remaps.len(), 3);
+        assert_eq!(remaps[0].to_name, Some(String::from("KEY_CONTEXT_MENU")));
+        assert!(!remaps[0].drop);
+        assert_eq!(remaps[1].key.device, Some(String::from("mce")));
+        assert_eq!(remaps[1].to_device, Some(String::from("remote")));
+        assert!(remaps[2].drop);
+    }
+
+    #[test]
+    fn remaps_validate() {
+        let check = |s: &str| FileConfig::from_str(s).unwrap().remaps();
+        assert_eq!(
+            check("[[remap]]\nto = \"A\""),
+            Err(String::from("remap 1: device or name must be set"))
+        );
+        assert_eq!(
+            check("[[remap]]\nname = \"A\""),
+            Err(String::from("remap 1: to, to_device or drop must be set"))
+        );
+        assert_eq!(
+            check("[[remap]]\nname = \"A\"\nto = \"B\"\ndrop = true"),
+            Err(String::from(
+                "remap 1: drop can't be used with to or to_device"
+            ))
+        );
+        assert_eq!(
+            check("[[remap]]\nname = \"A\"\nto = \"B C\""),
+            Err(String::from("remap 1: wrong name 'B C'"))
+        );
+        assert!(check("[[remap]]\nname = \"A\"\nto_device = \"\"").is_err());
+    }
+
+    #[test]
+    fn tcp_outputs() {
+        let fc = FileConfig::from_str(
+            "[[tcp]]\naddress = \"0.0.0.0:8765\"\nallow = [\"192.168.1.0/24\", \"::1\"]\n\
+            [[tcp]]\naddress = \"[::]:8766\"\nallow = []\n\
+            [[tcp]]\naddress = \"127.0.0.1:8767\"",
+        )
+        .unwrap();
+        let res = fc.tcp_outputs().unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].address.to_string(), "0.0.0.0:8765");
+        assert_eq!(
+            res[0]
+                .allow
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            vec!["192.168.1.0/24", "::1/128"]
+        );
+        assert_eq!(res[1].address.to_string(), "[::]:8766");
+        assert!(res[1].allow.is_empty());
+        assert_eq!(
+            res[2]
+                .allow
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            vec!["127.0.0.0/8", "::1/128"]
+        );
+
+        let check = |s: &str| FileConfig::from_str(s).unwrap().tcp_outputs();
+        assert!(check("[[tcp]]\naddress = \"localhost\"").is_err());
+        assert_eq!(
+            check("[[tcp]]\naddress = \"0.0.0.0:1\"\nallow = [\"10.0.0.0/40\"]"),
+            Err(String::from("tcp 1: wrong prefix length '40'"))
+        );
+        assert_eq!(
+            check("[[tcp]]\naddress = \"0.0.0.0:1\""),
+            Err(String::from(
+                "tcp 1: allow is needed on '0.0.0.0:1', [\"0.0.0.0/0\", \"::/0\"] allows everyone"
+            ))
+        );
+        assert!(check("[[tcp]]\naddress = \"0.0.0.0:1\"\nallow = [\"0.0.0.0/0\"]").is_ok());
+    }
+
+    #[test]
+    fn uinput() {
+        assert_eq!(FileConfig::default().uinput_output(), None);
+        let fc = FileConfig::from_str(
+            "[uinput]\nname = \"remote\"\n[uinput.keys]\nKEY_OK_HOLD = \"KEY_CONTEXT_MENU\"",
+        )
+        .unwrap();
+        let out = fc.uinput_output().unwrap();
+        assert_eq!(out.path, "/dev/uinput");
+        assert_eq!(out.name, "remote");
+        let keys = fc.keymap().unwrap();
+        assert_eq!(keys.code("KEY_OK_HOLD"), Some(0x1b6));
+        assert_eq!(
+            FileConfig::from_str("[uinput.keys]\nA = \"B\"")
+                .unwrap()
+                .keymap(),
+            Err(String::from("uinput: 'B' is not a key name"))
+        );
+        let keys = FileConfig::from_str("[uinput]\nmodifier = \"KEY_LEFTCTRL\"")
+            .unwrap()
+            .keymap()
+            .unwrap();
+        assert_eq!(keys.codes("KEY_OK_HOLD"), vec![29, 0x160]);
+        assert_eq!(
+            FileConfig::from_str("[uinput]\nmodifier = \"CTRL\"")
+                .unwrap()
+                .keymap(),
+            Err(String::from("uinput: 'CTRL' is not a key name"))
+        );
+    }
+
+    #[test]
+    fn fixed_changes() {
+        let start =
+            FileConfig::from_str("[uinput]\nname = \"remote\"\n[kodi]\nurl = \"tcp://kodi:9090\"")
+                .unwrap();
+        assert!(start.fixed_changes(&start).is_empty());
+        // keys and calls are reloaded
+        let fc = FileConfig::from_str(
+            "hold_ms = 700\n[uinput]\nname = \"remote\"\n\
+            [uinput.keys]\nKEY_OK_HOLD = \"KEY_MENU\"\n\
+            [kodi]\nurl = \"tcp://kodi:9090\"\n\
+            [[kodi.calls]]\nname = \"KEY_HOME\"\nmethod = \"Input.Home\"",
+        )
+        .unwrap();
+        assert!(fc.fixed_changes(&start).is_empty());
+        let fc = FileConfig::from_str(
+            "read_timeout_ms = 100\n[[tcp]]\naddress = \"127.0.0.1:1\"\n\
+            [kodi]\nurl = \"tcp://other:9090\"",
+        )
+        .unwrap();
+        assert_eq!(
+            fc.fixed_changes(&start),
+            vec!["read_timeout_ms", "[[tcp]]", "[uinput]", "[kodi]"]
+        );
+    }
+
+    #[test]
+    fn shared_settings() {
+        let settings = |ms| {
+            FileConfig::from_str(&format!("hold_ms = {}", ms))
+                .unwrap()
+                .settings(HoldConfig::default())
+                .unwrap()
+        };
+        let shared = SharedSettings::new(settings(500));
+        let mut a = shared.reader();
+        let mut b = shared.reader();
+        assert!(a.changed().is_none());
+        shared.set(settings(700));
+        assert!(a.changed().is_some());
+        assert!(a.changed().is_none());
+        assert!(b.changed().is_some());
+    }
+
+    #[test]
+    fn kodi() {
+        assert!(FileConfig::default().kodi_client().unwrap().is_none());
+        let fc = FileConfig::from_str(
+            "[kodi]\nurl = \"tcp://kodi:9090\"\n\
+            [[kodi.calls]]\nname = \"KEY_RIGHT_HOLD\"\nmethod = \"Player.Seek\"\n\
+            params = { playerid = 1, value = { seconds = 30 } }\n\
+            [[kodi.calls]]\nname = \"KEY_HOME\"\nmethod = \"Input.Home\"\nrepeat = true",
+        )
+        .unwrap();
+        let c = fc.kodi_client().unwrap().unwrap();
+        assert_eq!(c.endpoint().to_string(), "tcp://kodi:9090");
+        let calls = fc.kodi_calls().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            calls[0].params,
+            serde_json::json!({"playerid": 1, "value": {"seconds": 30}})
+        );
+        assert!(!calls[0].repeat);
+        assert!(calls[1].params.is_null());
+        assert!(calls[1].repeat);
+
+        let check = |s: &str| {
+            let fc = FileConfig::from_str(s).unwrap();
+            fc.kodi_client().and(fc.kodi_calls()).map(|_| ())
+        };
+        assert!(check("[kodi]\nurl = \"kodi:9090\"").is_err());
+        assert_eq!(
+            check("[kodi]\nurl = \"tcp://kodi:9090\"\npassword = \"p\""),
+            Err(String::from("kodi: password needs user"))
+        );
+        assert_eq!(
+            check("[kodi]\nurl = \"tcp://k:1\"\n[[kodi.calls]]\nname = \"A\"\nmethod = \"\""),
+            Err(String::from("kodi call 1: method can't be empty"))
+        );
+        assert_eq!(
+            check(
+                "[kodi]\nurl = \"tcp://k:1\"\n\
+                [[kodi.calls]]\nname = \"A\"\nmethod = \"M\"\nparams = 1"
+            ),
+            Err(String::from(
+                "kodi call 1: params must be a table or an array"
+            ))
+        );
+    }
+
+    #[test]
+    fn eventserver_output() {
+        assert_eq!(FileConfig::default().eventserver_output(), Ok(None));
+        let out = FileConfig::from_str("[eventserver]")
+            .unwrap()
+            .eventserver_output()
+            .unwrap()
+            .unwrap();
+        assert_eq!(out.address.to_string(), "127.0.0.1:9777");
+        assert_eq!(out.name, "lirc-changer");
+        assert_eq!(out.map, None);
+        assert_eq!(out.release, Duration::from_millis(5000));
+        let out = FileConfig::from_str(
+            "[eventserver]\naddress = \"[::1]:9777\"\nmap = \"R1\"\nrelease_ms = 150",
+        )
+        .unwrap()
+        .eventserver_output()
+        .unwrap()
+        .unwrap();
+        assert_eq!(out.address.to_string(), "[::1]:9777");
+        assert_eq!(out.map.as_deref(), Some("R1"));
+        assert_eq!(out.release, Duration::from_millis(150));
+
+        let check = |s: &str| FileConfig::from_str(s).unwrap().eventserver_output();
+        assert!(check("[eventserver]\naddress = \"kodi\"").is_err());
+        assert_eq!(
+            check("[eventserver]\nrelease_ms = 0"),
+            Err(String::from("eventserver: release_ms must be > 0"))
+        );
+    }
+
+    #[test]
+    fn actions() {
+        let fc = FileConfig::from_str(
+            "max_actions = 2\n\
+            [[actions]]\nname = \"KEY_POWER_HOLD\"\nshell = \"echo off | cec-client -s\"\n\
+            [[actions]]\nname = \"KEY_OK\"\ndevice = \"d\"\nrepeat = true\n\
+            argv = [\"systemctl\", \"restart\", \"kodi\"]\ntimeout_ms = 500",
+        )
+        .unwrap();
+        assert_eq!(fc.max_actions(), Ok(2));
+        let actions = fc.actions(&[]).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(
+            actions[0].commands,
+            vec![Command::Shell(String::from("echo off | cec-client -s"))]
+        );
+        assert_eq!(actions[0].key.name, Some(String::from("KEY_POWER_HOLD")));
+        assert_eq!(actions[0].repeat, 0);
+        assert_eq!(actions[0].timeout, Duration::from_millis(10000));
+        assert_eq!(
+            actions[1].commands,
+            vec![Command::Argv(vec![
+                String::from("systemctl"),
+                String::from("restart"),
+                String::from("kodi")
+            ])]
+        );
+        assert_eq!(actions[1].repeat, 1);
+        assert_eq!(actions[1].timeout, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn actions_validate() {
+        let check = |s: &str| FileConfig::from_str(s).unwrap().actions(&[]);
+        assert_eq!(
+            check("[[actions]]\nname = \"A\""),
+            Err(String::from("action 1: shell or argv must be set"))
+        );
+        assert_eq!(
+            check("[[actions]]\nname = \"A\"\nshell = \"x\"\nargv = [\"x\"]"),
+            Err(String::from(
+                "action 1: shell and argv can't be used together"
+            ))
+        );
+        assert_eq!(
+            check("[[actions]]\nname = \"A\"\nargv = []"),
+            Err(String::from("action 1: command can't be empty"))
+        );
+        assert!(check("[[actions]]\nname = \"A\"\nshell = \"x\"\ntimeout_ms = 0").is_err());
+        assert!(check("[[actions]]\nname = \"\"\nshell = \"x\"").is_err());
+        assert_eq!(
+            FileConfig::from_str("max_actions = 0")
+                .unwrap()
+                .max_actions(),
+            Err(String::from("max_actions must be > 0"))
+        );
+    }
+
+    #[test]
+    fn lircrc_actions() {
+        let entries = lircrc::parse(
+            "begin\nprog = irexec\nbutton = KEY_POWER_HOLD\nconfig = poweroff\nend\n\
+            begin\nprog = kodi\nbutton = KEY_OK\nconfig = Select\nend\n\
+            begin\nprog = irexec\nremote = mce\nbutton = *\nconfig = echo\nrepeat = 2\ndelay = 1\nend\n\
+            begin\nprog = irexec\nbutton = KEY_MENU\nmode = tv\nend\n\
+            begin\nprog = irexec\nbutton = KEY_UP\nend",
+        )
+        .unwrap();
+        let actions = FileConfig::default().lircrc_actions(&entries).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].key.name, Some(String::from("KEY_POWER_HOLD")));
+        assert_eq!(
+            actions[0].commands,
+            vec![Command::Shell(String::from("poweroff"))]
+        );
+        assert_eq!(actions[1].key.device, Some(String::from("mce")));
+        assert_eq!(actions[1].key.name, None);
+        assert_eq!((actions[1].repeat, actions[1].delay), (2, 1));
+        let fc = FileConfig::from_str("lircrc_prog = \"kodi\"").unwrap();
+        let actions = fc.lircrc_actions(&entries).unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].key.name, Some(String::from("KEY_OK")));
+        let fc = FileConfig::from_str("lircrc = \"/nonexistent/lircrc\"").unwrap();
+        assert!(fc.settings(HoldConfig::default()).is_err());
+    }
+
+    #[test]
+    fn lircrc_mode_config() {
+        let entries = lircrc::parse(
+            "begin\nprog = irexec\nbutton = KEY_RED\nmode = tv\nconfig = tv-on\nend\n\
+            begin\nprog = irexec\nbutton = KEY_1\nbutton = KEY_2\nmode = tv\nconfig = x\nend\n\
+            begin\nprog = irexec\nbutton = KEY_BLUE\nmode = radio\nend\n\
+            begin\nprog = irexec\nbutton = KEY_GREEN\nmode = t v\nconfig = x\nend",
+        )
+        .unwrap();
+        let fc = FileConfig::default();
+        // the entry runs its config, then the action enters the mode
+        let actions = fc.lircrc_actions(&entries[..2]).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].key.name, Some(String::from("KEY_RED")));
+        assert_eq!(actions[0].switch, Some(String::from("tv")));
+        assert_eq!(actions[1].key.name, Some(String::from("KEY_1+KEY_2")));
+        assert_eq!(actions[1].switch, Some(String::from("tv")));
+        let modes = fc.modes(&entries[..3]).unwrap();
+        assert_eq!(modes.len(), 1);
+        assert_eq!(modes[0].mode, "radio");
+        assert_eq!(
+            fc.lircrc_actions(&entries),
+            Err(String::from("lircrc line 19: wrong mode name 't v'"))
+        );
+    }
+
+    #[test]
+    fn lircrc_irexec() {
+        let entries = lircrc::parse(
+            "begin\n\tprog = irexec\n\tmode = tv\n\tflags = startup_mode\nend\n\
+            begin tv\n\
+            \tbegin\n\t\tprog = irexec\n\t\tbutton = key_power\n\
+            \t\tconfig = echo on\n\t\tconfig = echo off\n\t\tflags = quit\n\tend\n\
+            \tbegin\n\t\tprog = irexec\n\t\tremote = mce\n\t\tbutton = KEY_1\n\
+            \t\tbutton = KEY_2\n\t\tconfig = echo 12\n\t\tflags = once|toggle_reset\n\tend\n\
+            \tbegin\n\t\tprog = irexec\n\t\tbutton = key_red\n\t\tmode = radio\n\tend\n\
+            end tv\n",
+        )
+        .unwrap();
+        let fc = FileConfig::default();
+        assert_eq!(fc.startup_mode(&entries), Ok(Some(String::from("tv"))));
+        let actions = fc.actions(&entries).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].key.name, Some(String::from("key_power")));
+        assert!(actions[0].key.ignore_case && actions[0].quit && !actions[0].once);
+        assert_eq!(
+            actions[0].commands,
+            vec![
+                Command::Shell(String::from("echo on")),
+                Command::Shell(String::from("echo off"))
+            ]
+        );
+        // the sequence runs its config on the event the sequence makes
+        assert_eq!(actions[1].key.name, Some(String::from("KEY_1+KEY_2")));
+        assert!(actions[1].once);
+        let seqs = fc.sequences(&entries).unwrap();
+        assert_eq!(seqs.len(), 1);
+        assert_eq!(seqs[0].keys, vec!["KEY_1", "KEY_2"]);
+        assert_eq!(seqs[0].emit, "KEY_1+KEY_2");
+        assert_eq!(seqs[0].device, Some(String::from("mce")));
+        assert!(seqs[0].ignore_case);
+        let modes = fc.modes(&entries).unwrap();
+        assert_eq!(modes.len(), 1);
+        assert_eq!(modes[0].key.name, Some(String::from("key_red")));
+        assert_eq!(modes[0].key.mode, Some(String::from("tv")));
+        assert!(modes[0].key.ignore_case);
+        assert_eq!(fc.startup_mode(&[]), Ok(None));
+    }
+
+    #[test]
+    fn modes() {
+        let fc = FileConfig::from_str(
+            "[[modes]]\nmode = \"tv\"\nkey = \"KEY_MENU_HOLD\"\ntoggle = true\n\
+            [[modes]]\nmode = \"radio\"\nkey = \"KEY_RED\"\ndevice = \"d\"\nin_mode = \"tv\"",
+        )
+        .unwrap();
+        let entries = lircrc::parse(
+            "begin\nprog = irexec\nbutton = KEY_BLUE\nmode = radio\nend\n\
+            begin radio\nbegin\nprog = irexec\nbutton = KEY_OK\nconfig = x\nend\nend radio",
+        )
+        .unwrap();
+        let modes = fc.modes(&entries).unwrap();
+        assert_eq!(
+            modes,
+            vec![
+                ModeSwitch {
+                    key: KeyMatch {
+                        mode: None,
+                        device: None,
+                        name: Some(String::from("KEY_MENU_HOLD")),
+                        ignore_case: false,
+                    },
+                    mode: String::from("tv"),
+                    toggle: true,
+                },
+                ModeSwitch {
+                    key: KeyMatch {
+                        mode: Some(String::from("tv")),
+                        device: Some(String::from("d")),
+                        name: Some(String::from("KEY_RED")),
+                        ignore_case: false,
+                    },
+                    mode: String::from("radio"),
+                    toggle: false,
+                },
+                ModeSwitch {
+                    key: KeyMatch {
+                        mode: None,
+                        device: None,
+                        name: Some(String::from("KEY_BLUE")),
+                        ignore_case: true,
+                    },
+                    mode: String::from("radio"),
+                    toggle: false,
+                },
+            ]
+        );
+        let actions = fc.actions(&entries).unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].key.mode, Some(String::from("radio")));
+        let fc = FileConfig::from_str("[[modes]]\nmode = \"t v\"\nkey = \"A\"").unwrap();
+        assert_eq!(
+            fc.modes(&[]),
+            Err(String::from("mode 1: wrong mode name 't v'"))
+        );
+    }
+
+    #[test]
+    fn hold_rules_mode() {
+        let fc = FileConfig::from_str(
+            "[[rules]]\nname = \"KEY_UP\"\nhold_ms = 800\n\
+            [[rules]]\nmode = \"tv\"\ndevice = \"d\"\nhold = false",
+        )
+        .unwrap();
+        let rules = fc.hold_rules(&HoldConfig::default()).unwrap();
+        assert!(rules.find(DEFAULT_MODE, "d", "KEY_UP").enabled);
+        assert!(!rules.find("tv", "d", "KEY_UP").enabled);
+        assert!(rules.find("tv", "mce", "KEY_UP").enabled);
+        let fc = FileConfig::from_str("[[rules]]\nmode = \"\"\nname = \"A\"").unwrap();
+        assert!(fc.hold_rules(&HoldConfig::default()).is_err());
+    }
+
+    #[test]
+    fn backoff() {
+        let fc = FileConfig::from_str("reconnect_min_ms = 200").unwrap();
+        assert_eq!(
+            fc.backoff(None, None),
+            Ok(Backoff::new(
+                Duration::from_millis(200),
+                Duration::from_millis(5000)
+            ))
+        );
+        assert_eq!(
+            fc.backoff(None, Some(1000)),
+            Ok(Backoff::new(
+                Duration::from_millis(200),
+                Duration::from_millis(1000)
+            ))
+        );
+        assert_eq!(
+            fc.backoff(None, Some(100)),
+            Err(String::from(
+                "reconnect_max_ms (100) must be >= reconnect_min_ms (200)"
+            ))
+        );
+        assert!(fc.backoff(Some(0), None).is_err());
+    }
+
+    #[test]
+    fn read_timeout() {
+        assert_eq!(FileConfig::default().read_timeout(None), Ok(None));
+        let fc = FileConfig::from_str("read_timeout_ms = 60000").unwrap();
+        assert_eq!(
+            fc.read_timeout(None),
+            Ok(Some(Duration::from_millis(60000)))
+        );
+        assert_eq!(
+            fc.read_timeout(Some(100)),
+            Ok(Some(Duration::from_millis(100)))
+        );
+        assert!(fc.read_timeout(Some(0)).is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use crate::event::Event;
+use crate::logger;
+use crate::mode::{self, Mode};
+use crate::packet::Reply;
+use crate::server::{Listing, Msg};
+use crate::stats::{Inputs, Stats};
+use crossbeam_channel::{bounded, Sender};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Max wait for other threads answering a command
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the result of a config reload is sent: warnings about settings
+/// that are not reloaded, or the error
+pub type ReloadReply = Sender<Result<Vec<String>, String>>;
+
+/// Answers control socket commands
+pub struct Control {
+    pub mode: Mode,
+    /// mode announcements go here
+    pub events: Sender<Event>,
+    /// `INJECT` events go here, before the HOLD detector
+    pub inject: Sender<Event>,
+    pub clients: Sender<Msg>,
+    pub inputs: Arc<Inputs>,
+    pub reload: Sender<Option<ReloadReply>>,
+    pub stats: Arc<Stats>,
+    pub started: Instant,
+}
+
+fn reply(command: &str, res: Result<Vec<String>, String>) -> Reply {
+    match res {
+        Ok(data) => Reply {
+            command: command.to_string(),
+            success: true,
+            data,
+        },
+        Err(e) => Reply {
+            command: command.to_string(),
+            success: false,
+            data: vec![e],
+        },
+    }
+}
+
+impl Control {
+    /// Runs one command line, answers as lircd does
+    pub fn handle(&self, line: &str) -> Reply {
+        let command = line.trim();
+        let (name, args) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(n, a)| (n, a.trim()));
+        let words = args.split_whitespace().collect::<Vec<_>>();
+        let res = match (name.to_uppercase().as_str(), words.as_slice()) {
+            ("STATUS", []) => self.status(),
+            ("CLIENTS", []) => self.clients(),
+            ("STATS", []) => self.stats(),
+            ("RELOAD", []) => self.reload(),
+            ("SET", [k, l]) if k.eq_ignore_ascii_case("loglevel") => {
+                logger::set_level(l).map(|l| vec![l.to_string()])
+            }
+            ("INJECT", [_, ..]) => self.inject(args),
+            ("MODE", []) => Ok(vec![self.mode.get()]),
+            ("MODE", [m]) => self.set_mode(m),
+            ("", _) => Err(String::from("no command")),
+            _ => Err(format!("unknown command '{}'", command)),
+        };
+        reply(command, res)
+    }
+
+    fn listing(&self) -> Result<Listing, String> {
+        let (tx, rx) = bounded(1);
+        self.clients
+            .send(Msg::List(tx))
+            .map_err(|e| format!("can't list clients: {}", e))?;
+        rx.recv_timeout(REPLY_TIMEOUT)
+            .map_err(|e| format!("can't list clients: {}", e))
+    }
+
+    fn status(&self) -> Result<Vec<String>, String> {
+        let mut res = vec![format!("uptime {}", self.started.elapsed().as_secs())];
+        res.extend(self.inputs.lines());
+        res.push(format!("clients {}", self.listing()?.clients.len()));
+        res.push(format!("mode {}", self.mode.get()));
+        Ok(res)
+    }
+
+    fn clients(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .listing()?
+            .clients
+            .iter()
+            .map(|c| {
+                format!(
+                    "{} connected {} sent {}",
+                    c.id,
+                    c.connected.elapsed().as_secs(),
+                    c.sent
+                )
+            })
+            .collect())
+    }
+
+    fn stats(&self) -> Result<Vec<String>, String> {
+        let mut res = self.stats.lines();
+        res.push(format!("events_out {}", self.listing()?.sent));
+        Ok(res)
+    }
+
+    fn reload(&self) -> Result<Vec<String>, String> {
+        let (tx, rx) = bounded(1);
+        self.reload
+            .send(Some(tx))
+            .map_err(|e| format!("can't reload: {}", e))?;
+        rx.recv_timeout(REPLY_TIMEOUT)
+            .map_err(|e| format!("can't reload: {}", e))?
+    }
+
+    fn inject(&self, line: &str) -> Result<Vec<String>, String> {
+        let e = line.parse::<Event>().map_err(|e| e.to_string())?;
+        self.inject
+            .send(e)
+            .map_err(|e| format!("can't inject: {}", e))?;
+        Stats::inc(&self.stats.injected);
+        Ok(vec![])
+    }
+
+    fn set_mode(&self, m: &str) -> Result<Vec<String>, String> {
+        mode::check_name(m)?;
+        if self.mode.set(m) {
+            if let Err(err) = self.events.send(mode::announce(m)) {
+                log::warn!("{}", err);
+            }
+        }
+        Ok(vec![m.to_string()])
+    }
+}
+
+/// Serves `control` to every client of the listener
+pub fn serve(listener: UnixListener, control: Arc<Control>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let c = control.clone();
+                thread::spawn(move || handle_client(stream, c));
+            }
+            Err(err) => {
+                log::error!("Error: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+fn handle_client(stream: UnixStream, control: Arc<Control>) {
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(err) => {
+            log::warn!("Can't write to control client: {}", err);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let l = match line {
+            Ok(l) => l,
+            Err(err) => {
+                log::debug!("Can't read from control client: {}", err);
+                break;
+            }
+        };
+        log::info!("Control command '{}'", l);
+        let r = control.handle(&l);
+        if let Err(err) = writer.write_all(format!("{}\n", r).as_bytes()) {
+            log::warn!("Can't write to control client: {}", err);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::{Control, ReloadReply};
+    use crate::event::Event;
+    use crate::mode::{announce, Mode, DEFAULT_MODE};
+    use crate::server::{ClientInfo, Listing, Msg};
+    use crate::stats::{Inputs, Stats};
+    use crossbeam_channel::{unbounded, Receiver};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    struct Ends {
+        events: Receiver<Event>,
+        inject: Receiver<Event>,
+    }
+
+    fn control() -> (Control, Ends) {
+        let (events, r_events) = unbounded();
+        let (inject, r_inject) = unbounded();
+        let (clients, r_clients) = unbounded();
+        let (reload, r_reload) = unbounded::<Option<ReloadReply>>();
+        thread::spawn(move || {
+            for m in r_clients {
+                if let Msg::List(tx) = m {
+                    let c = ClientInfo {
+                        id: 3,
+                        connected: Instant::now(),
+                        sent: 7,
+                    };
+                    tx.send(Listing {
+                        sent: 9,
+                        clients: vec![c],
+                    })
+                    .unwrap();
+                }
+            }
+        });
+        thread::spawn(move || {
+            for (i, r) in r_reload.into_iter().enumerate() {
+                let res = match i {
+                    0 => Ok(vec![]),
+                    1 => Ok(vec![String::from(
+                        "[uinput] is used at start only, restart to apply it",
+                    )]),
+                    _ => Err(String::from("rule 1: wrong")),
+                };
+                if let Some(tx) = r {
+                    tx.send(res).unwrap();
+                }
+            }
+        });
+        let c = Control {
+            mode: Mode::default(),
+            events,
+            inject,
+            clients,
+            inputs: Arc::new(Inputs::new([
+                String::from("/run/lircd"),
+                String::from("evdev:/dev/input/event0"),
+            ])),
+            reload,
+            stats: Arc::new(Stats::default()),
+            started: Instant::now(),
+        };
+        let ends = Ends {
+            events: r_events,
+            inject: r_inject,
+        };
+        (c, ends)
+    }
+
+    #[test]
+    fn mode() {
+        let (c, ends) = control();
+        assert_eq!(
+            c.handle("MODE").to_string(),
+            "BEGIN\nMODE\nSUCCESS\nDATA\n1\ndefault\nEND"
+        );
+        let r = c.handle("mode tv\r");
+        assert!(r.success);
+        assert_eq!(r.command, "mode tv");
+        assert_eq!(c.mode.get(), "tv");
+        assert_eq!(ends.events.try_recv(), Ok(announce("tv")));
+        c.handle("MODE tv");
+        assert!(ends.events.try_recv().is_err());
+        c.handle("MODE default");
+        assert_eq!(ends.events.try_recv(), Ok(announce(DEFAULT_MODE)));
+    }
+
+    #[test]
+    fn status() {
+        let (c, _ends) = control();
+        assert_eq!(
+            c.handle("STATUS").data,
+            vec![
+                "uptime 0",
+                "input 0 /run/lircd disconnected",
+                "input 1 evdev:/dev/input/event0 disconnected",
+                "clients 1",
+                "mode default"
+            ]
+        );
+        assert_eq!(c.handle("clients").data, vec!["3 connected 0 sent 7"]);
+        let stats = c.handle("STATS").data;
+        assert_eq!(stats.len(), 6);
+        assert_eq!(stats[5], "events_out 9");
+    }
+
+    #[test]
+    fn reload() {
+        let (c, _ends) = control();
+        assert_eq!(
+            c.handle("RELOAD").to_string(),
+            "BEGIN\nRELOAD\nSUCCESS\nEND"
+        );
+        let r = c.handle("RELOAD");
+        assert!(r.success);
+        assert_eq!(
+            r.data,
+            vec!["[uinput] is used at start only, restart to apply it"]
+        );
+        let r = c.handle("RELOAD");
+        assert!(!r.success);
+        assert_eq!(r.data, vec!["rule 1: wrong"]);
+    }
+
+    #[test]
+    fn inject() {
+        let (c, ends) = control();
+        assert!(c.handle("INJECT 0000000000000001 00 KEY_OK d").success);
+        assert_eq!(
+            ends.inject.try_recv(),
+            Ok(Event::from_str("1 0 KEY_OK d").unwrap())
+        );
+        assert_eq!(c.handle("STATS").data[4], "injected 1");
+        let r = c.handle("INJECT 1 0 KEY_OK");
+        assert!(!r.success);
+        assert_eq!(r.data, vec!["expected 4 fields, got 3"]);
+        assert!(!c.handle("INJECT").success);
+    }
+
+    #[test]
+    fn errors() {
+        let (c, _ends) = control();
+        assert_eq!(
+            c.handle("MODE a b").to_string(),
+            "BEGIN\nMODE a b\nERROR\nDATA\n1\nunknown command 'MODE a b'\nEND"
+        );
+        assert!(!c.handle("").success);
+        assert!(!c.handle("STOP").success);
+        assert!(!c.handle("SET loglevel loud").success);
+        assert!(!c.handle("SET color red").success);
+    }
+}
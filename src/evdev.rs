@@ -0,0 +1,303 @@
+use crate::event::Event;
+use crate::keycodes;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::ErrorKind;
+use std::mem::size_of;
+use std::os::raw::c_long;
+use std::os::unix::io::AsRawFd;
+
+pub const EV_SYN: u16 = 0;
+pub const EV_KEY: u16 = 1;
+pub const EV_MSC: u16 = 4;
+pub const SYN_REPORT: u16 = 0;
+pub const MSC_SCAN: u16 = 4;
+/// `value` of `EV_KEY` events
+pub const KEY_RELEASE: i32 = 0;
+pub const KEY_PRESS: i32 = 1;
+pub const KEY_REPEAT: i32 = 2;
+/// device of the events, the same as lircd's devinput driver names it
+pub const DEVICE: &str = "devinput";
+
+/// `struct timeval` fields are C longs
+const LONG: usize = size_of::<c_long>();
+/// `ioctl` request of `linux/input.h` taking the device from other readers
+const EVIOCGRAB: libc::c_ulong = 0x40044590;
+
+/// `struct input_event` of the kernel
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct InputEvent {
+    pub sec: i64,
+    pub usec: i64,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+fn read_long(b: &[u8]) -> i64 {
+    match LONG {
+        8 => i64::from_ne_bytes(b[..8].try_into().unwrap()),
+        _ => i64::from(i32::from_ne_bytes(b[..4].try_into().unwrap())),
+    }
+}
+
+fn write_long(v: i64, out: &mut Vec<u8>) {
+    match LONG {
+        8 => out.extend(v.to_ne_bytes()),
+        _ => out.extend((v as i32).to_ne_bytes()),
+    }
+}
+
+impl InputEvent {
+    /// Size of the struct: 24 bytes on 64 bit platforms, 16 on 32 bit ones
+    pub const SIZE: usize = 2 * LONG + 8;
+
+    pub fn new(kind: u16, code: u16, value: i32) -> Self {
+        InputEvent {
+            kind,
+            code,
+            value,
+            ..InputEvent::default()
+        }
+    }
+
+    /// Reads the struct from `SIZE` bytes in native byte order
+    pub fn from_bytes(b: &[u8]) -> Self {
+        let t = 2 * LONG;
+        InputEvent {
+            sec: read_long(&b[..LONG]),
+            usec: read_long(&b[LONG..t]),
+            kind: u16::from_ne_bytes([b[t], b[t + 1]]),
+            code: u16::from_ne_bytes([b[t + 2], b[t + 3]]),
+            value: i32::from_ne_bytes(b[t + 4..t + 8].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(InputEvent::SIZE);
+        write_long(self.sec, &mut res);
+        write_long(self.usec, &mut res);
+        res.extend(self.kind.to_ne_bytes());
+        res.extend(self.code.to_ne_bytes());
+        res.extend(self.value.to_ne_bytes());
+        res
+    }
+}
+
+pub(crate) fn ioctl(file: &File, request: libc::c_ulong, arg: libc::c_int) -> std::io::Result<()> {
+    // SAFETY: the requests take an int argument or none
+    match unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Event device nobody else gets events of, such as the console or X, until
+/// it is dropped
+pub struct Grabbed {
+    file: File,
+}
+
+impl Grabbed {
+    pub fn new(file: File) -> std::io::Result<Self> {
+        ioctl(&file, EVIOCGRAB, 1)?;
+        Ok(Grabbed { file })
+    }
+}
+
+impl Read for Grabbed {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Drop for Grabbed {
+    fn drop(&mut self) {
+        if let Err(err) = ioctl(&self.file, EVIOCGRAB, 0) {
+            log::debug!("Can't release the device: {}", err);
+        }
+    }
+}
+
+/// Key name of the code, `KEY_0x<code>` for unknown ones
+pub fn key_name(code: u16) -> String {
+    match keycodes::name(code) {
+        Some(n) => n.to_string(),
+        None => format!("KEY_0x{:x}", code),
+    }
+}
+
+/// Turns key events into lircd style ones: a press makes repeat 0 and every
+/// autorepeat adds one. A release makes `<name>_UP` as lircd's `release`
+/// configs do. Other event types make nothing.
+#[derive(Debug, Default)]
+pub struct Keys {
+    /// repeats of the keys being held
+    repeats: HashMap<u16, u32>,
+}
+
+impl Keys {
+    pub fn new() -> Self {
+        Keys::default()
+    }
+
+    pub fn push(&mut self, ie: &InputEvent) -> Option<Event> {
+        if ie.kind != EV_KEY {
+            return None;
+        }
+        let e = Event {
+            code: u64::from(ie.code),
+            repeat: 0,
+            name: key_name(ie.code),
+            device: String::from(DEVICE),
+            source: 0,
+            end: false,
+        };
+        let repeat = match ie.value {
+            KEY_PRESS => {
+                self.repeats.insert(ie.code, 0);
+                0
+            }
+            KEY_REPEAT => {
+                let r = self.repeats.entry(ie.code).or_insert(0);
+                *r += 1;
+                *r
+            }
+            _ => return self.repeats.remove(&ie.code).map(|_| e.to_release()),
+        };
+        Some(Event { repeat, ..e })
+    }
+}
+
+/// Reads `input_event` structs until `r` ends and passes the key events to
+/// `f`. Returns false if `f` stopped the reading by returning false.
+pub fn read_events<R, F>(mut r: R, mut f: F) -> std::io::Result<bool>
+where
+    R: Read,
+    F: FnMut(Event) -> bool,
+{
+    let mut keys = Keys::new();
+    let mut buf = [0; InputEvent::SIZE];
+    loop {
+        match r.read_exact(&mut buf) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(true),
+            Err(err) => return Err(err),
+        }
+        let ie = InputEvent::from_bytes(&buf);
+        log::trace!("{:?}", ie);
+        if let Some(e) = keys.push(&ie) {
+            if !f(e) {
+                return Ok(false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evdev::{
+        read_events, InputEvent, Keys, EV_KEY, EV_MSC, EV_SYN, KEY_PRESS, KEY_RELEASE, KEY_REPEAT,
+        MSC_SCAN, SYN_REPORT,
+    };
+
+    const KEY_OK: u16 = 0x160;
+    const KEY_UP: u16 = 103;
+
+    fn key(code: u16, value: i32) -> Vec<u8> {
+        let mut res = InputEvent::new(EV_MSC, MSC_SCAN, 0x800f).to_bytes();
+        res.extend(InputEvent::new(EV_KEY, code, value).to_bytes());
+        res.extend(InputEvent::new(EV_SYN, SYN_REPORT, 0).to_bytes());
+        res
+    }
+
+    #[test]
+    fn bytes() {
+        let ie = InputEvent {
+            sec: 1700000000,
+            usec: 250,
+            kind: EV_KEY,
+            code: KEY_OK,
+            value: KEY_REPEAT,
+        };
+        let b = ie.to_bytes();
+        assert_eq!(b.len(), InputEvent::SIZE);
+        assert_eq!(InputEvent::from_bytes(&b), ie);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(InputEvent::SIZE, 24);
+    }
+
+    #[test]
+    fn keys() {
+        let mut k = Keys::new();
+        let e = k.push(&InputEvent::new(EV_KEY, KEY_OK, KEY_PRESS)).unwrap();
+        assert_eq!(e.to_string(), "0000000000000160 00 KEY_OK devinput");
+        assert!(k.push(&InputEvent::new(EV_SYN, SYN_REPORT, 0)).is_none());
+        let e = k
+            .push(&InputEvent::new(EV_KEY, KEY_OK, KEY_REPEAT))
+            .unwrap();
+        assert_eq!(e.repeat, 1);
+        let e = k
+            .push(&InputEvent::new(EV_KEY, KEY_OK, KEY_REPEAT))
+            .unwrap();
+        assert_eq!(e.repeat, 2);
+        let e = k
+            .push(&InputEvent::new(EV_KEY, KEY_OK, KEY_RELEASE))
+            .unwrap();
+        assert_eq!(e.to_string(), "0000000000000160 00 KEY_OK_UP devinput");
+        // a key released twice or not pressed since the start
+        assert!(k
+            .push(&InputEvent::new(EV_KEY, KEY_OK, KEY_RELEASE))
+            .is_none());
+        let e = k.push(&InputEvent::new(EV_KEY, KEY_OK, KEY_PRESS)).unwrap();
+        assert_eq!(e.repeat, 0);
+        let e = k.push(&InputEvent::new(EV_KEY, 0x2fe, KEY_PRESS)).unwrap();
+        assert_eq!(e.name, "KEY_0x2fe");
+    }
+
+    #[test]
+    fn recorded() {
+        let mut data = key(KEY_OK, KEY_PRESS);
+        data.extend(key(KEY_OK, KEY_REPEAT));
+        data.extend(key(KEY_OK, KEY_RELEASE));
+        data.extend(key(KEY_UP, KEY_PRESS));
+        data.extend(key(KEY_UP, KEY_RELEASE));
+        let mut got = vec![];
+        let res = read_events(data.as_slice(), |e| {
+            got.push(e.to_string());
+            true
+        });
+        assert!(res.unwrap());
+        assert_eq!(
+            got,
+            vec![
+                "0000000000000160 00 KEY_OK devinput",
+                "0000000000000160 01 KEY_OK devinput",
+                "0000000000000160 00 KEY_OK_UP devinput",
+                "0000000000000067 00 KEY_UP devinput",
+                "0000000000000067 00 KEY_UP_UP devinput",
+            ]
+        );
+        let mut n = 0;
+        let res = read_events(data.as_slice(), |_| {
+            n += 1;
+            n < 2
+        });
+        assert!(!res.unwrap());
+        assert_eq!(n, 2);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = key(KEY_OK, KEY_PRESS);
+        data.extend(&InputEvent::new(EV_KEY, KEY_OK, KEY_REPEAT).to_bytes()[..5]);
+        let mut n = 0;
+        let res = read_events(data.as_slice(), |_| {
+            n += 1;
+            true
+        });
+        assert!(res.unwrap());
+        assert_eq!(n, 1);
+    }
+}
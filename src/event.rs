@@ -1,72 +1,173 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// lircd writes packets of at most 256 bytes, including the newline
+pub const MAX_LINE_LEN: usize = 255;
+const MAX_CODE_LEN: usize = 16;
+/// lircd names release events of `release` configs `<name>_UP`
+pub const RELEASE_SUFFIX: &str = "_UP";
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Event {
-    pub id: String,
+    pub code: u64,
     pub repeat: u32,
     pub name: String,
     pub device: String,
+    /// index of the input the event was read from, not sent to clients
+    pub source: usize,
+    /// ends the press of `source` instead of pressing a key, goes from the
+    /// hold detector to the Kodi event server only
+    pub end: bool,
 }
 
-impl Event {
-    pub fn from_str(line: &str) -> Result<Self, String> {
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EventParseError {
+    /// line is not `<code> <repeat> <name> <device>`
+    FieldCount(usize),
+    BadCode(String),
+    BadRepeat(String),
+    LineTooLong(usize),
+}
+
+impl fmt::Display for EventParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventParseError::FieldCount(n) => write!(f, "expected 4 fields, got {}", n),
+            EventParseError::BadCode(s) => write!(f, "can't parse code '{}'", s),
+            EventParseError::BadRepeat(s) => write!(f, "can't parse repeat '{}'", s),
+            EventParseError::LineTooLong(n) => {
+                write!(f, "line of {} bytes is longer than {}", n, MAX_LINE_LEN)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EventParseError {}
+
+fn parse_hex(s: &str, max_len: usize) -> Option<u64> {
+    if s.is_empty() || s.len() > max_len || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(s, 16).ok()
+}
+
+impl FromStr for Event {
+    type Err = EventParseError;
+
+    /// Parses a lircd line `<code> <repeat> <name> <device>`, code and repeat
+    /// are hexadecimal. Name and device are any non whitespace strings.
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        if line.len() > MAX_LINE_LEN {
+            return Err(EventParseError::LineTooLong(line.len()));
+        }
         let strs = line.split_whitespace().collect::<Vec<_>>();
         if strs.len() != 4 {
-            return Err(format!("'{}' is not of len 4", line));
-        }
-        //todo: parse as hexadecimal string
-        match u32::from_str_radix(strs[1], 16) {
-            Ok(n) => Ok(Event {
-                id: strs[0].to_string(),
-                repeat: n,
-                name: strs[2].to_string(),
-                device: strs[3].to_string(),
-            }),
-            Err(e) => Err(format!("can't parse '{}': {}", strs[1], e)),
+            return Err(EventParseError::FieldCount(strs.len()));
         }
+        let code = parse_hex(strs[0], MAX_CODE_LEN)
+            .ok_or_else(|| EventParseError::BadCode(strs[0].to_string()))?;
+        let repeat = parse_hex(strs[1], 8)
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| EventParseError::BadRepeat(strs[1].to_string()))?;
+        Ok(Event {
+            code,
+            repeat,
+            name: strs[2].to_string(),
+            device: strs[3].to_string(),
+            source: 0,
+            end: false,
+        })
     }
+}
 
+impl Event {
     pub fn to_str(&self) -> String {
-        format!(
-            "{} {:x} {} {}",
-            self.id, self.repeat, self.name, self.device
-        )
+        self.to_string()
     }
 
-    pub fn to_hold(&self) -> Event {
+    pub fn to_hold(&self, suffix: &str) -> Event {
         Event {
-            id: self.id.clone(),
+            code: self.code,
             repeat: 0,
-            name: self.name.clone() + "_HOLD",
+            name: self.name.clone() + suffix,
             device: self.device.clone(),
+            source: self.source,
+            end: false,
+        }
+    }
+
+    /// Names a series of short presses: `<name>_DOUBLE`, `<name>_TRIPLE`.
+    pub fn to_taps(&self, count: u32) -> Event {
+        match count {
+            0 | 1 => self.to_new(),
+            2 => self.to_hold("_DOUBLE"),
+            _ => self.to_hold("_TRIPLE"),
+        }
+    }
+
+    /// Makes the release event of the key, as lircd does
+    pub fn to_release(&self) -> Event {
+        self.to_hold(RELEASE_SUFFIX)
+    }
+
+    /// Tells if the event is the release of the `press` key. Names alone
+    /// can't tell it: KEY_UP is a key too.
+    pub fn is_release_of(&self, press: &Event) -> bool {
+        self.device == press.device
+            && self.name.strip_suffix(RELEASE_SUFFIX) == Some(press.name.as_str())
+    }
+
+    /// Makes the event ending the press of the key
+    pub fn to_end(&self) -> Event {
+        Event {
+            end: true,
+            ..self.clone()
         }
     }
 
     pub fn to_new(&self) -> Event {
         Event {
-            id: self.id.clone(),
+            code: self.code,
             repeat: 0,
             name: self.name.clone(),
             device: self.device.clone(),
+            source: self.source,
+            end: false,
         }
     }
 }
 
+impl fmt::Display for Event {
+    /// Writes the event as lircd does
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:016x} {:02x} {} {}",
+            self.code, self.repeat, self.name, self.device
+        )
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::event::Event;
+    use crate::event::{Event, EventParseError};
+    use std::str::FromStr;
     #[test]
     fn from_str() {
-        assert_eq!(Event::from_str(""), Err(String::from("'' is not of len 4")));
+        assert_eq!(Event::from_str(""), Err(EventParseError::FieldCount(0)));
         assert_eq!(
             Event::from_str("a 9 e"),
-            Err(String::from("'a 9 e' is not of len 4"))
+            Err(EventParseError::FieldCount(3))
         );
         assert_eq!(
             Event::from_str("a 9 e d"),
             Ok(Event {
-                id: String::from("a"),
+                code: 10,
                 repeat: 9,
                 name: String::from("e"),
-                device: String::from("d")
+                device: String::from("d"),
+                source: 0,
+                end: false,
             })
         );
     }
@@ -76,24 +177,130 @@ mod tests {
         assert_eq!(
             Event::from_str("a b e d"),
             Ok(Event {
-                id: String::from("a"),
+                code: 10,
                 repeat: 11,
                 name: String::from("e"),
-                device: String::from("d")
+                device: String::from("d"),
+                source: 0,
+                end: false,
+            })
+        );
+        assert_eq!(
+            Event::from_str("00000000800f7422 1f KEY_OK mceusb"),
+            Ok(Event {
+                code: 0x800f7422,
+                repeat: 31,
+                name: String::from("KEY_OK"),
+                device: String::from("mceusb"),
+                source: 0,
+                end: false,
             })
         );
+        assert_eq!(
+            Event::from_str("ffffffffffffffff 0 e d").unwrap().code,
+            u64::MAX
+        );
+    }
+
+    #[test]
+    fn from_str_lircd_names() {
+        let e = Event::from_str("000000037ff07bee 00\tok/enter  Apple_A1156-v2.0\r").unwrap();
+        assert_eq!(e.name, "ok/enter");
+        assert_eq!(e.device, "Apple_A1156-v2.0");
+        let e = Event::from_str(" 0 0 KEY_UP_HOLD ąčę ").unwrap();
+        assert_eq!(e.name, "KEY_UP_HOLD");
+        assert_eq!(e.device, "ąčę");
+    }
+
+    #[test]
+    fn from_str_bad_code() {
+        assert_eq!(
+            Event::from_str("xyz 0 e d"),
+            Err(EventParseError::BadCode(String::from("xyz")))
+        );
+        assert_eq!(
+            Event::from_str("+a 0 e d"),
+            Err(EventParseError::BadCode(String::from("+a")))
+        );
+        assert_eq!(
+            Event::from_str("10000000000000000 0 e d"),
+            Err(EventParseError::BadCode(String::from("10000000000000000")))
+        );
+    }
+
+    #[test]
+    fn from_str_bad_repeat() {
+        assert_eq!(
+            Event::from_str("a g e d"),
+            Err(EventParseError::BadRepeat(String::from("g")))
+        );
+        assert_eq!(
+            Event::from_str("a -1 e d"),
+            Err(EventParseError::BadRepeat(String::from("-1")))
+        );
+        assert_eq!(
+            Event::from_str("a 100000000 e d"),
+            Err(EventParseError::BadRepeat(String::from("100000000")))
+        );
+    }
+
+    #[test]
+    fn from_str_too_long() {
+        let line = format!("a 0 {} d", "e".repeat(300));
+        assert_eq!(
+            Event::from_str(&line),
+            Err(EventParseError::LineTooLong(306))
+        );
+    }
+
+    #[test]
+    fn parse_error_display() {
+        assert_eq!(
+            EventParseError::FieldCount(3).to_string(),
+            "expected 4 fields, got 3"
+        );
+        assert_eq!(
+            EventParseError::BadCode(String::from("x")).to_string(),
+            "can't parse code 'x'"
+        );
+        assert_eq!(
+            EventParseError::BadRepeat(String::from("x")).to_string(),
+            "can't parse repeat 'x'"
+        );
+        assert_eq!(
+            EventParseError::LineTooLong(300).to_string(),
+            "line of 300 bytes is longer than 255"
+        );
     }
 
     #[test]
     fn to_hold() {
         let e = Event::from_str("a b e d").unwrap();
         assert_eq!(
-            e.to_hold(),
+            e.to_hold("_HOLD"),
             Event {
-                id: String::from("a"),
+                code: 10,
                 repeat: 0,
                 name: String::from("e_HOLD"),
-                device: String::from("d")
+                device: String::from("d"),
+                source: 0,
+                end: false,
+            }
+        );
+    }
+
+    #[test]
+    fn to_hold_suffix() {
+        let e = Event::from_str("a b e d").unwrap();
+        assert_eq!(
+            e.to_hold("_LONG"),
+            Event {
+                code: 10,
+                repeat: 0,
+                name: String::from("e_LONG"),
+                device: String::from("d"),
+                source: 0,
+                end: false,
             }
         );
     }
@@ -101,37 +308,73 @@ mod tests {
     #[test]
     fn to_str() {
         let e = Event {
-            id: String::from("a"),
+            code: 10,
             repeat: 11,
             name: String::from("e"),
             device: String::from("d"),
+            source: 1,
+            end: false,
         };
-        assert_eq!(e.to_str(), "a b e d");
+        assert_eq!(e.to_str(), "000000000000000a 0b e d");
         let e = Event {
-            id: String::from("a"),
+            code: 0x800f7422,
             repeat: 16,
             name: String::from("e"),
             device: String::from("d"),
+            source: 0,
+            end: false,
         };
-        assert_eq!(e.to_str(), "a 10 e d");
+        assert_eq!(e.to_str(), "00000000800f7422 10 e d");
+        assert_eq!(e.to_string(), "00000000800f7422 10 e d");
+    }
+
+    #[test]
+    fn to_str_round_trip() {
+        let line = "000000037ff07bee 01 KEY_OK apple";
+        assert_eq!(Event::from_str(line).unwrap().to_string(), line);
     }
 
     #[test]
     fn to_new() {
         let e = Event {
-            id: String::from("a"),
+            code: 10,
             repeat: 11,
             name: String::from("e"),
             device: String::from("d"),
+            source: 2,
+            end: false,
         };
         assert_eq!(
             e.to_new(),
             Event {
-                id: String::from("a"),
+                code: 10,
                 repeat: 0,
                 name: String::from("e"),
-                device: String::from("d")
+                device: String::from("d"),
+                source: 2,
+                end: false,
             }
         );
     }
+
+    #[test]
+    fn to_taps() {
+        let e = Event::from_str("a b e d").unwrap();
+        assert_eq!(e.to_taps(1), e.to_new());
+        assert_eq!(e.to_taps(2).name, "e_DOUBLE");
+        assert_eq!(e.to_taps(2).repeat, 0);
+        assert_eq!(e.to_taps(3).name, "e_TRIPLE");
+    }
+
+    #[test]
+    fn release() {
+        let e = Event::from_str("a 3 KEY_OK d").unwrap();
+        let r = e.to_release();
+        assert_eq!(r.to_string(), "000000000000000a 00 KEY_OK_UP d");
+        assert!(r.is_release_of(&e));
+        assert!(!e.is_release_of(&e));
+        assert!(!r.is_release_of(&Event::from_str("a 0 KEY_OK other").unwrap()));
+        let up = Event::from_str("1 0 KEY_UP d").unwrap();
+        assert!(!up.is_release_of(&Event::from_str("1 0 KEY_OK d").unwrap()));
+    }
 }
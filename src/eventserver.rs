@@ -0,0 +1,442 @@
+use crate::event::Event;
+use crate::mode::MODE_DEVICE;
+use crossbeam_channel::{after, select, Receiver, Sender};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9777";
+pub const DEFAULT_NAME: &str = "lirc-changer";
+pub const DEFAULT_RELEASE_MS: u64 = 5000;
+/// Kodi forgets clients silent for a minute
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+pub const HEADER_SIZE: usize = 32;
+const SIGNATURE: &[u8; 4] = b"XBMC";
+const MAJOR: u8 = 2;
+const MINOR: u8 = 0;
+/// packet types
+pub const PT_HELO: u16 = 0x01;
+pub const PT_BYE: u16 = 0x02;
+pub const PT_BUTTON: u16 = 0x03;
+pub const PT_PING: u16 = 0x05;
+pub const PT_NOTIFICATION: u16 = 0x07;
+/// BUTTON flags
+pub const BT_USE_NAME: u16 = 0x01;
+pub const BT_DOWN: u16 = 0x02;
+pub const BT_UP: u16 = 0x04;
+pub const BT_USE_AMOUNT: u16 = 0x08;
+pub const BT_QUEUE: u16 = 0x10;
+pub const BT_NO_REPEAT: u16 = 0x20;
+const ICON_NONE: u8 = 0;
+
+/// Kodi EventServer to send buttons to, set at start only
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EventServerOutput {
+    pub address: SocketAddr,
+    /// client name shown by Kodi
+    pub name: String,
+    /// button map like `KB` or `R1`, `LI:<device>` of Lircmap.xml if `None`
+    pub map: Option<String>,
+    /// a button is released when its events stop and its press end does not
+    /// come for so long
+    pub release: Duration,
+}
+
+/// Packet of one part: the header, all numbers big endian, and the payload
+pub fn packet(kind: u16, seq: u32, uid: u32, payload: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(HEADER_SIZE + payload.len());
+    res.extend(SIGNATURE);
+    res.extend([MAJOR, MINOR]);
+    res.extend(kind.to_be_bytes());
+    res.extend(seq.to_be_bytes());
+    // max seq, the count of parts
+    res.extend(1u32.to_be_bytes());
+    res.extend((payload.len() as u16).to_be_bytes());
+    res.extend(uid.to_be_bytes());
+    res.extend([0; 10]);
+    res.extend(payload);
+    res
+}
+
+fn push_str(out: &mut Vec<u8>, s: &str) {
+    out.extend(s.as_bytes());
+    out.push(0);
+}
+
+pub fn helo(name: &str) -> Vec<u8> {
+    let mut res = vec![];
+    push_str(&mut res, name);
+    res.push(ICON_NONE);
+    // port and two reserved u32
+    res.extend([0; 10]);
+    res
+}
+
+pub fn button(flags: u16, map: &str, name: &str) -> Vec<u8> {
+    let mut res = vec![];
+    // code, not used with names
+    res.extend(0u16.to_be_bytes());
+    res.extend(flags.to_be_bytes());
+    // amount
+    res.extend(0u16.to_be_bytes());
+    push_str(&mut res, map);
+    push_str(&mut res, name);
+    res
+}
+
+pub fn notification(title: &str, message: &str) -> Vec<u8> {
+    let mut res = vec![];
+    push_str(&mut res, title);
+    push_str(&mut res, message);
+    res.push(ICON_NONE);
+    // reserved
+    res.extend([0; 4]);
+    res
+}
+
+/// Presses Kodi buttons on events. The first event of a press holds its
+/// button down, Kodi repeats it, and the `end` event from the hold detector
+/// releases it, as does another button. Buttons whose end does not come for
+/// `release` are released on the timer. Mode switches are shown as
+/// notifications.
+pub struct EventServer {
+    socket: UdpSocket,
+    name: String,
+    /// HELO went through, cleared when Kodi is not there
+    greeted: bool,
+    map: Option<String>,
+    release: Duration,
+    ping: Duration,
+    seq: u32,
+    uid: u32,
+    /// input, map and name of the button down
+    down: Option<(usize, String, String)>,
+    release_at: Option<Instant>,
+    sent_at: Instant,
+}
+
+impl EventServer {
+    /// Says HELO to Kodi, it does not answer. HELO is said again before
+    /// the next packet if a send fails, as Kodi forgets clients on restart.
+    pub fn connect(out: &EventServerOutput) -> std::io::Result<Self> {
+        let local = match out.address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(out.address)?;
+        let uid = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos())
+            ^ std::process::id();
+        let mut res = EventServer {
+            socket,
+            name: out.name.clone(),
+            greeted: false,
+            map: out.map.clone(),
+            release: out.release,
+            ping: PING_INTERVAL,
+            seq: 0,
+            uid,
+            down: None,
+            release_at: None,
+            sent_at: Instant::now(),
+        };
+        res.send(PT_HELO, &helo(&out.name))?;
+        Ok(res)
+    }
+
+    pub fn with_ping(mut self, ping: Duration) -> Self {
+        self.ping = ping;
+        self
+    }
+
+    fn send(&mut self, kind: u16, payload: &[u8]) -> std::io::Result<()> {
+        if !self.greeted && kind != PT_HELO {
+            self.send(PT_HELO, &helo(&self.name))?;
+        }
+        self.seq += 1;
+        self.sent_at = Instant::now();
+        let res = self.socket.send(&packet(kind, self.seq, self.uid, payload));
+        match res {
+            Ok(_) if kind == PT_HELO => self.greeted = true,
+            Ok(_) => {}
+            Err(_) => self.greeted = false,
+        }
+        res.map(|_| ())
+    }
+
+    fn release_button(&mut self) -> std::io::Result<()> {
+        self.release_at = None;
+        match self.down.take() {
+            Some((_, map, name)) => self.send(PT_BUTTON, &button(BT_USE_NAME | BT_UP, &map, &name)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn push(&mut self, e: &Event) -> std::io::Result<()> {
+        if e.end {
+            return match self.down.as_ref().is_some_and(|d| d.0 == e.source) {
+                true => self.release_button(),
+                false => Ok(()),
+            };
+        }
+        if e.device == MODE_DEVICE {
+            let mode = e.name.strip_prefix("MODE_").unwrap_or(&e.name);
+            return self.send(PT_NOTIFICATION, &notification("Mode", mode));
+        }
+        let map = match &self.map {
+            Some(m) => m.clone(),
+            None => format!("LI:{}", e.device),
+        };
+        let pressed = (e.source, map, e.name.clone());
+        if e.repeat > 0 && self.down.as_ref() == Some(&pressed) {
+            // still down, Kodi repeats it
+            self.release_at = Some(Instant::now() + self.release);
+            return Ok(());
+        }
+        self.release_button()?;
+        let flags = BT_USE_NAME | BT_DOWN;
+        self.send(PT_BUTTON, &button(flags, &pressed.1, &pressed.2))?;
+        self.down = Some(pressed);
+        self.release_at = Some(Instant::now() + self.release);
+        Ok(())
+    }
+
+    /// Returns the time when `tick` must be called next
+    pub fn deadline(&self) -> Instant {
+        let ping = self.sent_at + self.ping;
+        self.release_at.map_or(ping, |r| r.min(ping))
+    }
+
+    /// Releases the button whose events stopped, pings Kodi when idle
+    pub fn tick(&mut self) -> std::io::Result<()> {
+        let now = Instant::now();
+        if self.release_at.is_some_and(|r| now >= r) {
+            self.release_button()?;
+        }
+        if now >= self.sent_at + self.ping {
+            self.send(PT_PING, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Passes events from `data` to `out` pressing their buttons, until
+    /// `data` or `cl` is closed. Says BYE at the end.
+    pub fn run(mut self, data: Receiver<Event>, out: Sender<Event>, cl: Receiver<u32>) {
+        loop {
+            let timer = after(self.deadline().saturating_duration_since(Instant::now()));
+            select! {
+                recv(timer) -> _ => {
+                    if let Err(err) = self.tick() {
+                        log::error!("Can't send to Kodi event server: {}", err);
+                    }
+                }
+                recv(data) -> msg => {
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Err(err) = self.push(&received) {
+                        log::error!("Can't send to Kodi event server: {}", err);
+                    }
+                    if !received.end {
+                        out.send(received).unwrap();
+                    }
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in event server");
+                    break;
+                }
+            }
+        }
+        if let Err(err) = self.release_button().and_then(|_| self.send(PT_BYE, &[])) {
+            log::warn!("Can't say bye to Kodi event server: {}", err);
+        }
+        log::info!("exit event server");
+    }
+}
+
+/// Resolves `host:port` of the event server
+pub fn resolve(address: &str) -> Result<SocketAddr, String> {
+    address
+        .to_socket_addrs()
+        .map_err(|e| format!("wrong address '{}': {}", address, e))?
+        .next()
+        .ok_or_else(|| format!("no address found for '{}'", address))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::Event;
+    use crate::eventserver::{
+        button, helo, notification, packet, EventServer, EventServerOutput, BT_DOWN, BT_UP,
+        BT_USE_NAME, HEADER_SIZE, PT_BUTTON, PT_BYE, PT_HELO, PT_NOTIFICATION, PT_PING,
+    };
+    use crate::mode;
+    use crossbeam_channel::unbounded;
+    use std::net::UdpSocket;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Packet read by a stand-in Kodi
+    #[derive(Debug, PartialEq)]
+    struct Got {
+        kind: u16,
+        seq: u32,
+        payload: Vec<u8>,
+    }
+
+    fn kodi() -> UdpSocket {
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        s.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        s
+    }
+
+    fn recv(s: &UdpSocket) -> Got {
+        let mut buf = [0; 1024];
+        let n = s.recv(&mut buf).unwrap();
+        let b = &buf[..n];
+        assert_eq!(&b[..6], b"XBMC\x02\x00");
+        let size = usize::from(u16::from_be_bytes([b[16], b[17]]));
+        assert_eq!(n, HEADER_SIZE + size);
+        Got {
+            kind: u16::from_be_bytes([b[6], b[7]]),
+            seq: u32::from_be_bytes(b[8..12].try_into().unwrap()),
+            payload: b[HEADER_SIZE..].to_vec(),
+        }
+    }
+
+    fn connect(k: &UdpSocket, map: Option<&str>) -> EventServer {
+        let out = EventServerOutput {
+            address: k.local_addr().unwrap(),
+            name: String::from("changer"),
+            map: map.map(String::from),
+            release: Duration::from_millis(50),
+        };
+        EventServer::connect(&out).unwrap()
+    }
+
+    fn ev(s: &str) -> Event {
+        Event::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn header() {
+        let p = packet(PT_BUTTON, 7, 0x01020304, b"ab");
+        assert_eq!(p.len(), 34);
+        assert_eq!(
+            &p[..HEADER_SIZE],
+            b"XBMC\x02\x00\x00\x03\x00\x00\x00\x07\x00\x00\x00\x01\x00\x02\
+            \x01\x02\x03\x04\0\0\0\0\0\0\0\0\0\0"
+        );
+        assert_eq!(&p[HEADER_SIZE..], b"ab");
+    }
+
+    #[test]
+    fn payloads() {
+        assert_eq!(helo("tv"), b"tv\0\0\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(
+            button(BT_USE_NAME | BT_DOWN, "KB", "up"),
+            b"\0\0\0\x03\0\0KB\0up\0"
+        );
+        assert_eq!(notification("a", "b"), b"a\0b\0\0\0\0\0\0");
+    }
+
+    #[test]
+    fn press_release() {
+        let k = kodi();
+        let mut es = connect(&k, None);
+        let got = recv(&k);
+        assert_eq!((got.kind, got.seq), (PT_HELO, 1));
+        assert_eq!(got.payload, helo("changer"));
+
+        let down = BT_USE_NAME | BT_DOWN;
+        let up = BT_USE_NAME | BT_UP;
+        // the button is held down for the press, Kodi repeats it
+        es.push(&ev("1 0 KEY_VOLUMEUP mce")).unwrap();
+        es.push(&ev("1 1 KEY_VOLUMEUP mce")).unwrap();
+        let got = recv(&k);
+        assert_eq!((got.kind, got.seq), (PT_BUTTON, 2));
+        assert_eq!(got.payload, button(down, "LI:mce", "KEY_VOLUMEUP"));
+        // another button releases the one down
+        es.push(&ev("1 0 KEY_OK_HOLD mce")).unwrap();
+        let got = recv(&k);
+        assert_eq!((got.kind, got.seq), (PT_BUTTON, 3));
+        assert_eq!(got.payload, button(up, "LI:mce", "KEY_VOLUMEUP"));
+        assert_eq!(recv(&k).payload, button(down, "LI:mce", "KEY_OK_HOLD"));
+        // and so does the end of its press, not of presses of other inputs
+        let mut end = ev("1 0 KEY_OK mce").to_end();
+        end.source = 1;
+        es.push(&end).unwrap();
+        end.source = 0;
+        es.push(&end).unwrap();
+        assert_eq!(recv(&k).payload, button(up, "LI:mce", "KEY_OK_HOLD"));
+        es.push(&end).unwrap();
+        // the timer releases a button whose end does not come
+        es.push(&ev("1 0 KEY_OK mce")).unwrap();
+        assert_eq!(recv(&k).payload, button(down, "LI:mce", "KEY_OK"));
+        thread::sleep(Duration::from_millis(60));
+        es.tick().unwrap();
+        assert_eq!(recv(&k).payload, button(up, "LI:mce", "KEY_OK"));
+        es.tick().unwrap();
+        es.push(&mode::announce("tv")).unwrap();
+        let got = recv(&k);
+        assert_eq!((got.kind, got.seq), (PT_NOTIFICATION, 8));
+        assert_eq!(got.payload, notification("Mode", "tv"));
+    }
+
+    #[test]
+    fn helo_again() {
+        let k = kodi();
+        let address = k.local_addr().unwrap();
+        let mut es = connect(&k, None);
+        assert_eq!(recv(&k).kind, PT_HELO);
+        drop(k);
+        // a send fails once the closed port is reported
+        let failed = (0..20).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            es.push(&ev("1 0 KEY_OK d")).is_err()
+        });
+        assert!(failed);
+        let k = UdpSocket::bind(address).unwrap();
+        k.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        es.push(&ev("1 0 KEY_OK d")).unwrap();
+        assert_eq!(recv(&k).kind, PT_HELO);
+    }
+
+    #[test]
+    fn ping() {
+        let k = kodi();
+        let es = connect(&k, Some("R1")).with_ping(Duration::from_millis(50));
+        assert_eq!(recv(&k).kind, PT_HELO);
+        let (tx, rx) = unbounded();
+        let (otx, orx) = unbounded();
+        let (t_close, r_close) = unbounded::<u32>();
+        let h = thread::spawn(move || es.run(rx, otx, r_close));
+        assert_eq!(recv(&k).kind, PT_PING);
+        assert_eq!(recv(&k).kind, PT_PING);
+        tx.send(ev("1 0 KEY_OK d")).unwrap();
+        assert_eq!(orx.recv().unwrap(), ev("1 0 KEY_OK d"));
+        let got = recv(&k);
+        assert_eq!(got.payload, button(BT_USE_NAME | BT_DOWN, "R1", "KEY_OK"));
+        tx.send(ev("1 0 KEY_OK d").to_end()).unwrap();
+        assert_eq!(
+            recv(&k).payload,
+            button(BT_USE_NAME | BT_UP, "R1", "KEY_OK")
+        );
+        drop(t_close);
+        h.join().unwrap();
+        // the end is not passed on
+        assert!(orx.try_recv().is_err());
+        let mut got = recv(&k);
+        while got.kind == PT_PING {
+            got = recv(&k);
+        }
+        assert_eq!(got.kind, PT_BYE);
+    }
+}
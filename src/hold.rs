@@ -0,0 +1,911 @@
+use crate::config::{HoldConfig, HoldRule, HoldRules, SettingsReader};
+use crate::event::{Event, RELEASE_SUFFIX};
+use crate::mode::Mode;
+use crossbeam_channel::{after, never, select, Receiver, Sender};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Time source of the detector, replaced in tests
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Collects short presses of multi-tap keys until the tap window expires
+struct Taps {
+    pending: Option<(Event, HoldRule, u32)>,
+    at: Instant,
+}
+
+impl Taps {
+    fn new(now: Instant) -> Self {
+        Taps {
+            pending: None,
+            at: now,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    fn press(&mut self, e: Event, rule: &HoldRule, now: Instant, out: &mut Vec<Event>) {
+        if rule.taps <= 1 {
+            self.flush(out);
+            out.push(e);
+            return;
+        }
+        let count = match self.pending.take() {
+            Some((p, p_rule, count))
+                if p.name == e.name && p.device == e.device && now <= self.at + p_rule.tap =>
+            {
+                count + 1
+            }
+            Some((p, _, count)) => {
+                out.push(p.to_taps(count));
+                1
+            }
+            None => 1,
+        };
+        log::debug!("tap {}", count);
+        if count >= rule.taps {
+            out.push(e.to_taps(count));
+        } else {
+            self.pending = Some((e, rule.clone(), count));
+            self.at = now;
+        }
+    }
+
+    fn flush(&mut self, out: &mut Vec<Event>) {
+        if let Some((p, _, count)) = self.pending.take() {
+            out.push(p.to_taps(count));
+        }
+    }
+
+    fn expire(&mut self, now: Instant, out: &mut Vec<Event>) {
+        if let Some((_, p_rule, _)) = &self.pending {
+            if now > self.at + p_rule.tap {
+                self.flush(out);
+            }
+        }
+    }
+}
+
+/// Device and name of a key
+type Key = (String, String);
+
+fn key(e: &Event) -> Key {
+    (e.device.clone(), e.name.clone())
+}
+
+/// Press in progress of one input
+struct Press {
+    prev: Option<(Event, HoldRule)>,
+    at: Instant,
+    taps: Taps,
+    timer: Option<Instant>,
+    /// key down, its release ends the press
+    down: Option<Event>,
+    /// keys pressed and not released yet, the current one or overlapped
+    held: HashSet<Key>,
+    /// keys that send release events, their presses are not ended by the
+    /// tick
+    releases: HashSet<Key>,
+    /// every key of the input sends releases
+    all_release: bool,
+    /// send the end of presses whose events went out
+    ends: bool,
+    /// event of the press in progress that went out, its end is not sent
+    /// yet
+    open: Option<Event>,
+    /// last key event and its time, a press of a key without releases is
+    /// over when its repeats stop for a tick
+    last: Option<(Event, Instant)>,
+}
+
+impl Press {
+    fn new(now: Instant) -> Self {
+        Press {
+            prev: None,
+            at: now,
+            taps: Taps::new(now),
+            timer: None,
+            down: None,
+            held: HashSet::new(),
+            releases: HashSet::new(),
+            all_release: false,
+            ends: false,
+            open: None,
+            last: None,
+        }
+    }
+
+    /// Key released by the event, if it is a release of a held key or of
+    /// one that sent releases before
+    fn released(&self, e: &Event) -> Option<Key> {
+        let name = e.name.strip_suffix(RELEASE_SUFFIX)?;
+        let k = (e.device.clone(), name.to_string());
+        (self.held.contains(&k) || self.releases.contains(&k)).then_some(k)
+    }
+
+    fn waits_release(&self, e: &Event) -> bool {
+        self.all_release || self.releases.contains(&key(e))
+    }
+
+    fn push(
+        &mut self,
+        received: Event,
+        rule: &HoldRule,
+        now: Instant,
+        tick: Duration,
+    ) -> Vec<Event> {
+        let mut res = vec![];
+        let release = self.released(&received).is_some();
+        let down = self.down.is_some();
+        if !release {
+            if !self.continues(&received) {
+                self.close(&mut res);
+            }
+            self.last = Some((received.clone(), now));
+        }
+        let (events, current) = self.key(received, rule, now, tick);
+        let ended = release && down && self.down.is_none();
+        self.out(events, current, ended, &mut res);
+        self.timer = self.timer.into_iter().chain(self.open_until(tick)).min();
+        res
+    }
+
+    /// Events of the key, true if the last of them is of the press in
+    /// progress
+    fn key(
+        &mut self,
+        received: Event,
+        rule: &HoldRule,
+        now: Instant,
+        tick: Duration,
+    ) -> (Vec<Event>, bool) {
+        let mut res = vec![];
+        if let Some(k) = self.released(&received) {
+            self.held.remove(&k);
+            self.releases.insert(k);
+            if !self
+                .down
+                .as_ref()
+                .is_some_and(|d| received.is_release_of(d))
+            {
+                log::debug!("drop release of other press {}", received.name);
+                return (res, false);
+            }
+            log::debug!("release");
+            self.down = None;
+            if let Some((e, e_rule)) = self.prev.take() {
+                self.end(e, &e_rule, now, &mut res);
+            }
+            self.timer = (!self.taps.is_empty()).then(|| now + tick);
+            return (res, false);
+        }
+        if received.repeat == 0 {
+            self.held.insert(key(&received));
+            self.down = Some(received.clone());
+        }
+        if !rule.enabled {
+            log::debug!("no hold");
+            if let Some((e, e_rule)) = self.prev.take() {
+                self.taps.press(e.to_new(), &e_rule, now, &mut res);
+            }
+            self.taps.flush(&mut res);
+            res.push(received);
+            self.timer = None;
+            return (res, true);
+        }
+        let mut current = false;
+        match self.prev.take() {
+            None => {
+                log::debug!("none");
+                if received.repeat == 0 {
+                    self.start(received, rule.clone(), now);
+                }
+            }
+            Some((e, e_rule)) => {
+                if e.name != received.name {
+                    log::debug!("!=name");
+                    self.taps.press(e.to_new(), &e_rule, now, &mut res);
+                    self.start(received, rule.clone(), now);
+                } else if e.repeat + 1 != received.repeat {
+                    log::debug!("!=repeat");
+                    self.taps.press(e.to_new(), &e_rule, now, &mut res);
+                    if received.repeat == 0 {
+                        self.start(received, rule.clone(), now);
+                    }
+                } else if now > self.at + e_rule.last().after {
+                    log::debug!("long");
+                    self.taps.flush(&mut res);
+                    res.push(e.to_hold(&e_rule.last().suffix));
+                    current = true;
+                } else {
+                    log::debug!("skip");
+                    self.prev = Some((received, e_rule))
+                }
+            }
+        }
+        self.timer = match &self.prev {
+            Some((e, e_rule)) if self.waits_release(e) => Some(self.at + e_rule.last().after),
+            _ => self.pending().then(|| now + tick),
+        };
+        (res, current)
+    }
+
+    fn tick(&mut self, now: Instant, tick: Duration) -> Vec<Event> {
+        let mut res = vec![];
+        if self.timer.is_none_or(|t| now < t) {
+            return res;
+        }
+        if self.open_until(tick).is_some_and(|t| now >= t) {
+            self.close(&mut res);
+        }
+        let mut events = vec![];
+        let mut current = false;
+        match self.prev.take() {
+            None => {
+                self.taps.expire(now, &mut events);
+            }
+            // still down at the time of the last stage
+            Some((e, rule)) if self.waits_release(&e) => {
+                self.taps.flush(&mut events);
+                events.push(e.to_hold(&rule.last().suffix));
+                current = true;
+            }
+            Some((e, rule)) => self.end(e, &rule, now, &mut events),
+        }
+        self.out(events, current, false, &mut res);
+        self.timer = (!self.taps.is_empty()).then(|| now + tick);
+        self.timer = self.timer.into_iter().chain(self.open_until(tick)).min();
+        res
+    }
+
+    /// Passes `events` on, followed by the end of their press unless it is
+    /// in progress. `ended` tells the press in progress was released.
+    fn out(&mut self, mut events: Vec<Event>, current: bool, ended: bool, res: &mut Vec<Event>) {
+        if let Some(e) = events.last() {
+            self.open = Some(e.clone());
+        }
+        let finished = !events.is_empty() || ended;
+        res.append(&mut events);
+        if !current && finished {
+            self.close(res);
+        }
+    }
+
+    /// Sends the end of the press whose event went out
+    fn close(&mut self, res: &mut Vec<Event>) {
+        if let Some(e) = self.open.take() {
+            if self.ends {
+                res.push(e.to_end());
+            }
+        }
+    }
+
+    /// Tells if the key event repeats the last one
+    fn continues(&self, e: &Event) -> bool {
+        self.last.as_ref().is_some_and(|(l, _)| {
+            l.name == e.name && l.device == e.device && l.repeat + 1 == e.repeat
+        })
+    }
+
+    /// Time when the press whose event went out is over, if it ends on the
+    /// tick
+    fn open_until(&self, tick: Duration) -> Option<Instant> {
+        let (e, at) = self.last.as_ref()?;
+        (self.ends && self.open.is_some() && !self.waits_release(e)).then(|| *at + tick)
+    }
+
+    /// Makes the short press or the hold event of a finished press
+    fn end(&mut self, e: Event, rule: &HoldRule, now: Instant, out: &mut Vec<Event>) {
+        match rule.stage(now - self.at) {
+            Some(stage) => {
+                self.taps.flush(out);
+                out.push(e.to_hold(&stage.suffix));
+            }
+            None => self.taps.press(e.to_new(), rule, now, out),
+        }
+    }
+
+    fn start(&mut self, e: Event, rule: HoldRule, now: Instant) {
+        self.prev = Some((e, rule));
+        self.at = now;
+    }
+
+    fn pending(&self) -> bool {
+        self.prev.is_some() || !self.taps.is_empty()
+    }
+}
+
+/// Turns lircd repeats into short press, `_HOLD` and multi-tap events.
+///
+/// The detector is a plain state machine: feed it with `push`, call `tick`
+/// when `deadline` passes and send the returned events further. Presses are
+/// tracked per event `source`, so repeats of several inputs may interleave.
+///
+/// Release events (`<name>_UP`) end a press at once and are not passed on,
+/// releases of keys other than the one pressed last are dropped. Once a key
+/// has sent one, its presses no longer end when repeats stop for a tick,
+/// only on release or when the last hold stage is reached. Keys without
+/// releases keep ending on the tick. Keys of inputs given to
+/// `with_releases` wait for the release from the first press.
+///
+/// With `with_ends` the end of a press whose event went out, a HOLD made
+/// while the key is down or keys with `hold = false`, follows as an `end`
+/// event once the press is over, so the event server holds its button down
+/// until then.
+pub struct HoldDetector<C: Clock = SystemClock> {
+    cfg: HoldConfig,
+    rules: HoldRules,
+    clock: C,
+    presses: HashMap<usize, Press>,
+    mode: Mode,
+    /// inputs whose keys all send releases
+    releases: HashSet<usize>,
+    ends: bool,
+}
+
+impl HoldDetector<SystemClock> {
+    pub fn new(cfg: HoldConfig, rules: HoldRules) -> Self {
+        HoldDetector::with_clock(cfg, rules, SystemClock)
+    }
+}
+
+impl<C: Clock> HoldDetector<C> {
+    pub fn with_clock(cfg: HoldConfig, rules: HoldRules, clock: C) -> Self {
+        let now = clock.now();
+        let mut press = Press::new(now);
+        press.timer = Some(now + cfg.idle);
+        HoldDetector {
+            cfg,
+            rules,
+            clock,
+            presses: HashMap::from([(0, press)]),
+            mode: Mode::default(),
+            releases: HashSet::new(),
+            ends: false,
+        }
+    }
+
+    /// Picks rules by the shared `mode`
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Presses of the `sources` inputs wait for their release, as all their
+    /// keys send one
+    pub fn with_releases(mut self, sources: impl IntoIterator<Item = usize>) -> Self {
+        self.releases = sources.into_iter().collect();
+        for (s, p) in self.presses.iter_mut() {
+            p.all_release = self.releases.contains(s);
+        }
+        self
+    }
+
+    /// Sends `end` events after presses, for the event server
+    pub fn with_ends(mut self, ends: bool) -> Self {
+        self.ends = ends;
+        for p in self.presses.values_mut() {
+            p.ends = ends;
+        }
+        self
+    }
+
+    /// Replaces rules, a press in progress keeps its rule
+    pub fn update(&mut self, cfg: HoldConfig, rules: HoldRules) {
+        self.cfg = cfg;
+        self.rules = rules;
+    }
+
+    /// Returns the time when `tick` must be called next
+    pub fn deadline(&self) -> Option<Instant> {
+        self.presses.values().filter_map(|p| p.timer).min()
+    }
+
+    /// Handles an event from lircd
+    pub fn push(&mut self, received: Event) -> Vec<Event> {
+        let now = self.clock.now();
+        let rule = self
+            .rules
+            .find(&self.mode.get(), &received.device, &received.name);
+        let all_release = self.releases.contains(&received.source);
+        let ends = self.ends;
+        self.presses
+            .entry(received.source)
+            .or_insert_with(|| Press {
+                all_release,
+                ends,
+                ..Press::new(now)
+            })
+            .push(received, rule, now, self.cfg.tick)
+    }
+
+    /// Finishes presses whose repeats stopped and expires multi-taps
+    pub fn tick(&mut self) -> Vec<Event> {
+        let now = self.clock.now();
+        let mut sources = self.presses.keys().copied().collect::<Vec<_>>();
+        sources.sort_unstable();
+        let mut res = vec![];
+        for s in sources {
+            let press = self.presses.get_mut(&s).unwrap();
+            if press.timer.is_some_and(|t| now >= t) {
+                log::debug!("on timer of input {}", s);
+                res.append(&mut press.tick(now, self.cfg.tick));
+            }
+        }
+        res
+    }
+
+    /// Reads events from `data` until it or `cl` is closed
+    pub fn run(
+        &mut self,
+        data: Receiver<Event>,
+        out: Sender<Event>,
+        mut settings: SettingsReader,
+        cl: Receiver<u32>,
+    ) {
+        let start = self.clock.now();
+        fn show(dur: Duration) {
+            log::debug!("Elapsed: {}.{:03} sec", dur.as_secs(), dur.subsec_millis());
+        }
+
+        loop {
+            let update = match self.deadline() {
+                Some(d) => after(d.saturating_duration_since(self.clock.now())),
+                None => never(),
+            };
+            select! {
+                recv(update) -> _ => {
+                    show(self.clock.now() - start);
+                    for e in self.tick() {
+                        out.send(e).unwrap();
+                    }
+                }
+                recv(data) -> msg => {
+                    show(self.clock.now() - start);
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Some(s) = settings.changed() {
+                        log::info!("update hold rules");
+                        self.update(s.hold, s.rules.clone());
+                    }
+                    log::debug!("Got process {}", received.to_str());
+                    for e in self.push(received) {
+                        out.send(e).unwrap();
+                    }
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in process");
+                    break;
+                }
+            }
+        }
+        log::info!("exit process");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{FileConfig, HoldConfig};
+    use crate::event::Event;
+    use crate::hold::{Clock, HoldDetector};
+    use crate::mode::Mode;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone)]
+    struct TestClock {
+        start: Instant,
+        now: Rc<Cell<Instant>>,
+    }
+
+    impl TestClock {
+        fn new() -> Self {
+            let start = Instant::now();
+            TestClock {
+                start,
+                now: Rc::new(Cell::new(start)),
+            }
+        }
+
+        fn set(&self, ms: u64) {
+            self.now.set(self.start + Duration::from_millis(ms));
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+    }
+
+    fn detector(config: &str) -> (HoldDetector<TestClock>, TestClock) {
+        let fc = FileConfig::from_str(config).unwrap();
+        let cfg = fc.hold_config(None, None, None).unwrap();
+        let rules = fc.hold_rules(&cfg).unwrap();
+        let clock = TestClock::new();
+        (HoldDetector::with_clock(cfg, rules, clock.clone()), clock)
+    }
+
+    fn ev(repeat: u32, name: &str) -> Event {
+        Event {
+            code: 1,
+            repeat,
+            name: String::from(name),
+            device: String::from("d"),
+            source: 0,
+            end: false,
+        }
+    }
+
+    fn names(events: Vec<Event>) -> Vec<String> {
+        events.into_iter().map(|e| e.name).collect()
+    }
+
+    /// Pushes a press with `repeats` repeats every 80 ms starting at `at` ms
+    fn press(
+        d: &mut HoldDetector<TestClock>,
+        clock: &TestClock,
+        name: &str,
+        at: u64,
+        repeats: u32,
+    ) -> Vec<String> {
+        let mut res = vec![];
+        for i in 0..=repeats {
+            clock.set(at + u64::from(i) * 80);
+            res.append(&mut names(d.push(ev(i, name))));
+        }
+        res
+    }
+
+    #[test]
+    fn idle_timer() {
+        let (mut d, clock) = detector("");
+        assert_eq!(d.deadline(), Some(clock.start + HoldConfig::default().idle));
+        clock.set(1000);
+        assert!(d.tick().is_empty());
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn short_press() {
+        let (mut d, clock) = detector("");
+        assert!(press(&mut d, &clock, "A", 0, 2).is_empty());
+        assert_eq!(d.deadline(), Some(clock.start + Duration::from_millis(260)));
+        clock.set(200);
+        assert!(d.tick().is_empty());
+        clock.set(260);
+        let res = d.tick();
+        assert_eq!(res, vec![ev(0, "A")]);
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn hold_on_repeat() {
+        let (mut d, clock) = detector("");
+        // repeats at 0..480 ms are skipped, the one at 560 ms makes HOLD
+        assert_eq!(press(&mut d, &clock, "A", 0, 7), vec!["A_HOLD"]);
+        // next repeats are ignored
+        let mut e = ev(8, "A");
+        clock.set(640);
+        assert!(d.push(e.clone()).is_empty());
+        e.repeat = 9;
+        clock.set(720);
+        assert!(d.push(e).is_empty());
+        clock.set(900);
+        assert!(d.tick().is_empty());
+    }
+
+    #[test]
+    fn hold_boundary() {
+        let (mut d, clock) = detector("hold_ms = 480");
+        // the last repeat comes exactly at 480 ms
+        assert!(press(&mut d, &clock, "A", 0, 6).is_empty());
+        clock.set(580);
+        assert_eq!(names(d.tick()), vec!["A_HOLD"]);
+
+        let (mut d, clock) = detector("hold_ms = 580");
+        assert!(press(&mut d, &clock, "A", 0, 6).is_empty());
+        clock.set(580);
+        assert_eq!(names(d.tick()), vec!["A"]);
+    }
+
+    #[test]
+    fn repeat_gap() {
+        let (mut d, clock) = detector("");
+        assert!(press(&mut d, &clock, "A", 0, 1).is_empty());
+        clock.set(160);
+        // a lost repeat finishes the press, the new one is not a start
+        assert_eq!(d.push(ev(3, "A")), vec![ev(0, "A")]);
+        assert_eq!(d.deadline(), None);
+        // a new press right after
+        assert!(press(&mut d, &clock, "A", 300, 0).is_empty());
+        clock.set(330);
+        assert_eq!(names(d.push(ev(0, "A"))), vec!["A"]);
+        clock.set(430);
+        assert_eq!(names(d.tick()), vec!["A"]);
+    }
+
+    #[test]
+    fn name_change() {
+        let (mut d, clock) = detector("");
+        assert!(press(&mut d, &clock, "A", 0, 2).is_empty());
+        clock.set(200);
+        assert_eq!(names(d.push(ev(0, "B"))), vec!["A"]);
+        clock.set(300);
+        assert_eq!(names(d.tick()), vec!["B"]);
+    }
+
+    #[test]
+    fn stages() {
+        let config = "[[rules]]\nname = \"A\"\nstages_ms = [300, 700, 1100]";
+        let (mut d, clock) = detector(config);
+        assert!(press(&mut d, &clock, "A", 0, 5).is_empty());
+        clock.set(500);
+        assert_eq!(names(d.tick()), vec!["A_HOLD"]);
+        assert!(press(&mut d, &clock, "A", 1000, 10).is_empty());
+        clock.set(1900);
+        assert_eq!(names(d.tick()), vec!["A_HOLD2"]);
+        assert_eq!(press(&mut d, &clock, "A", 3000, 14), vec!["A_HOLD3"]);
+    }
+
+    #[test]
+    fn taps() {
+        let config = "[[rules]]\nname = \"A\"\ntaps = 3";
+        let (mut d, clock) = detector(config);
+        assert!(press(&mut d, &clock, "A", 0, 0).is_empty());
+        clock.set(100);
+        assert!(d.tick().is_empty());
+        assert!(press(&mut d, &clock, "A", 250, 0).is_empty());
+        clock.set(350);
+        assert!(d.tick().is_empty());
+        clock.set(600);
+        assert!(d.tick().is_empty());
+        clock.set(700);
+        assert_eq!(names(d.tick()), vec!["A_DOUBLE"]);
+        assert_eq!(d.deadline(), None);
+
+        for at in [1000, 1200, 1400] {
+            assert!(press(&mut d, &clock, "A", at, 0).is_empty());
+            clock.set(at + 100);
+            let res = names(d.tick());
+            if at == 1400 {
+                assert_eq!(res, vec!["A_TRIPLE"]);
+            } else {
+                assert!(res.is_empty());
+            }
+        }
+
+        // other key flushes pending taps
+        assert!(press(&mut d, &clock, "A", 2000, 0).is_empty());
+        clock.set(2100);
+        assert!(d.tick().is_empty());
+        assert!(press(&mut d, &clock, "B", 2150, 0).is_empty());
+        clock.set(2250);
+        assert_eq!(names(d.tick()), vec!["A", "B"]);
+    }
+
+    #[test]
+    fn mode() {
+        let config = "[[rules]]\nmode = \"tv\"\nname = \"V\"\nhold = false";
+        let (d, clock) = detector(config);
+        let m = Mode::default();
+        let mut d = d.with_mode(m.clone());
+        assert_eq!(press(&mut d, &clock, "V", 0, 7), vec!["V_HOLD"]);
+        clock.set(1000);
+        assert!(d.tick().is_empty());
+        m.set("tv");
+        assert_eq!(press(&mut d, &clock, "V", 2000, 1), vec!["V", "V"]);
+    }
+
+    #[test]
+    fn update() {
+        let (mut d, clock) = detector("");
+        assert!(press(&mut d, &clock, "A", 0, 3).is_empty());
+        let fc = FileConfig::from_str("hold_ms = 1000").unwrap();
+        let cfg = fc.hold_config(None, None, None).unwrap();
+        let rules = fc.hold_rules(&cfg).unwrap();
+        d.update(cfg, rules);
+        // the press in progress keeps the old rule
+        let mut res = vec![];
+        for i in 4..=7 {
+            clock.set(u64::from(i) * 80);
+            res.append(&mut names(d.push(ev(i, "A"))));
+        }
+        assert_eq!(res, vec!["A_HOLD"]);
+        assert!(press(&mut d, &clock, "A", 2000, 7).is_empty());
+    }
+
+    #[test]
+    fn sources() {
+        let (mut d, clock) = detector("");
+        let mut res = vec![];
+        // presses of two inputs interleave, both make HOLD
+        for i in 0..=7 {
+            clock.set(u64::from(i) * 80);
+            res.append(&mut d.push(ev(i, "A")));
+            clock.set(u64::from(i) * 80 + 10);
+            let mut e = ev(i, "B");
+            e.source = 1;
+            res.append(&mut d.push(e));
+        }
+        assert_eq!(res[0].name, "A_HOLD");
+        assert_eq!(res[0].source, 0);
+        assert_eq!(res[1].name, "B_HOLD");
+        assert_eq!(res[1].source, 1);
+        assert_eq!(res.len(), 2);
+        // a short press of one input ends on its own timer
+        clock.set(1000);
+        let mut e = ev(0, "C");
+        e.source = 1;
+        assert!(d.push(e).is_empty());
+        clock.set(1100);
+        assert!(d.push(ev(0, "D")).is_empty());
+        assert_eq!(names(d.tick()), vec!["C"]);
+        clock.set(1200);
+        assert_eq!(names(d.tick()), vec!["D"]);
+    }
+
+    #[test]
+    fn release() {
+        let config = "[[rules]]\nname = \"S\"\nstages_ms = [300, 700]";
+        let (mut d, clock) = detector(config);
+        assert!(press(&mut d, &clock, "A", 0, 0).is_empty());
+        clock.set(50);
+        assert_eq!(d.push(ev(0, "A_UP")), vec![ev(0, "A")]);
+        assert_eq!(d.deadline(), None);
+        // releases are known now, a press waits for its release
+        assert!(press(&mut d, &clock, "A", 200, 0).is_empty());
+        assert_eq!(d.deadline(), Some(clock.start + Duration::from_millis(700)));
+        clock.set(450);
+        assert_eq!(names(d.push(ev(0, "A_UP"))), vec!["A"]);
+        // the hold is made on time without repeats, the release is dropped
+        assert!(press(&mut d, &clock, "A", 1000, 0).is_empty());
+        clock.set(1500);
+        assert_eq!(names(d.tick()), vec!["A_HOLD"]);
+        clock.set(1700);
+        assert!(d.push(ev(0, "A_UP")).is_empty());
+        assert_eq!(d.deadline(), None);
+        // the stage reached at the release
+        assert!(press(&mut d, &clock, "S", 2000, 4).is_empty());
+        clock.set(2500);
+        assert_eq!(names(d.push(ev(0, "S_UP"))), vec!["S_HOLD"]);
+        // KEY_UP is a key, not a release of KEY_OK
+        assert!(press(&mut d, &clock, "KEY_OK", 3000, 0).is_empty());
+        clock.set(3050);
+        assert_eq!(names(d.push(ev(0, "KEY_UP"))), vec!["KEY_OK"]);
+        clock.set(3100);
+        assert_eq!(names(d.push(ev(0, "KEY_UP_UP"))), vec!["KEY_UP"]);
+    }
+
+    #[test]
+    fn release_per_key() {
+        let (mut d, clock) = detector("");
+        assert!(press(&mut d, &clock, "A", 0, 0).is_empty());
+        clock.set(50);
+        assert_eq!(names(d.push(ev(0, "A_UP"))), vec!["A"]);
+        // B of the same input sends no releases, its press ends on the tick
+        assert!(press(&mut d, &clock, "B", 200, 1).is_empty());
+        clock.set(380);
+        assert_eq!(names(d.tick()), vec!["B"]);
+        // A still waits for its release
+        assert!(press(&mut d, &clock, "A", 1000, 1).is_empty());
+        clock.set(1300);
+        assert!(d.tick().is_empty());
+        clock.set(1350);
+        assert_eq!(names(d.push(ev(0, "A_UP"))), vec!["A"]);
+    }
+
+    #[test]
+    fn first_press_release() {
+        let (d, clock) = detector("");
+        let mut d = d.with_releases([0]);
+        let mut res = vec![];
+        // evdev repeats start 500 ms after the press, later than the tick
+        let times = (0..=12).map(|i| (i, if i == 0 { 0 } else { 375 + i * 125 }));
+        for (i, at) in times.chain([(13, 2000)]) {
+            while let Some(t) = d
+                .deadline()
+                .filter(|t| *t <= clock.start + Duration::from_millis(at))
+            {
+                clock.now.set(t);
+                res.append(&mut names(d.tick()));
+            }
+            clock.set(at);
+            let e = match i {
+                13 => ev(0, "KEY_OK_UP"),
+                _ => ev(i as u32, "KEY_OK"),
+            };
+            res.append(&mut names(d.push(e)));
+        }
+        assert_eq!(res, vec!["KEY_OK_HOLD"]);
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn ends() {
+        let config = "[[rules]]\nname = \"V\"\nhold = false";
+        let (d, clock) = detector(config);
+        let mut d = d.with_ends(true);
+        let names = |events: Vec<Event>| {
+            let name = |e: Event| match e.end {
+                true => format!("end {}", e.name),
+                false => e.name,
+            };
+            events.into_iter().map(name).collect::<Vec<_>>()
+        };
+        // a short press ends as it goes out
+        assert!(press(&mut d, &clock, "A", 0, 1).is_empty());
+        clock.set(180);
+        assert_eq!(names(d.tick()), vec!["A", "end A"]);
+        // a HOLD made on a repeat ends when the repeats stop
+        assert_eq!(press(&mut d, &clock, "A", 1000, 9), vec!["A_HOLD"]);
+        clock.set(1800);
+        assert!(d.tick().is_empty());
+        clock.set(1820);
+        assert_eq!(names(d.tick()), vec!["end A_HOLD"]);
+        assert_eq!(d.deadline(), None);
+        // keys without hold end on the next press
+        assert_eq!(press(&mut d, &clock, "V", 2000, 1), vec!["V", "V"]);
+        clock.set(2100);
+        assert_eq!(names(d.push(ev(0, "A"))), vec!["end V"]);
+        // a HOLD of a key with releases ends on the release
+        clock.set(2150);
+        assert_eq!(names(d.push(ev(0, "A_UP"))), vec!["A", "end A"]);
+        assert!(press(&mut d, &clock, "A", 3000, 0).is_empty());
+        clock.set(3500);
+        assert_eq!(names(d.tick()), vec!["A_HOLD"]);
+        clock.set(4000);
+        assert!(d.tick().is_empty());
+        assert_eq!(names(d.push(ev(0, "A_UP"))), vec!["end A_HOLD"]);
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn stray_release() {
+        let (mut d, clock) = detector("");
+        // B is pressed while A is down, as evdev keys may overlap
+        assert!(press(&mut d, &clock, "A", 0, 0).is_empty());
+        clock.set(50);
+        assert_eq!(names(d.push(ev(0, "B"))), vec!["A"]);
+        clock.set(100);
+        assert!(d.push(ev(0, "A_UP")).is_empty());
+        clock.set(150);
+        assert_eq!(names(d.push(ev(0, "B_UP"))), vec!["B"]);
+        // a second release of a key
+        clock.set(200);
+        assert!(d.push(ev(0, "B_UP")).is_empty());
+        assert_eq!(d.deadline(), None);
+    }
+
+    #[test]
+    fn no_hold() {
+        let config = "[[rules]]\nname = \"V\"\nhold = false";
+        let (mut d, clock) = detector(config);
+        assert!(press(&mut d, &clock, "A", 0, 0).is_empty());
+        clock.set(50);
+        assert_eq!(names(d.push(ev(0, "V"))), vec!["A", "V"]);
+        clock.set(130);
+        assert_eq!(names(d.push(ev(1, "V"))), vec!["V"]);
+        assert_eq!(d.deadline(), None);
+    }
+}
@@ -0,0 +1,449 @@
+use crate::backoff::Backoff;
+use crate::evdev;
+use socket2::{SockRef, TcpKeepalive};
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+const TCP_PREFIX: &str = "tcp://";
+const EVDEV_PREFIX: &str = "evdev://";
+/// suffix of evdev inputs other programs read too
+const NO_GRAB: &str = "?nograb";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// an idle peer is probed after this time...
+const KEEPALIVE_TIME: Duration = Duration::from_secs(30);
+/// ...every interval, and is dead after so many unanswered probes
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const KEEPALIVE_RETRIES: u32 = 3;
+
+/// Where lircd events come from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Source {
+    /// unix socket path
+    Unix(String),
+    /// `host:port` of lircd started with `--listen`
+    Tcp(String),
+    /// `/dev/input/eventN` device path, read without lircd. A grabbed
+    /// device sends its keys to nobody else.
+    Evdev { path: String, grab: bool },
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    /// Parses `tcp://host:port`, `evdev:///dev/input/eventN[?nograb]` or a
+    /// unix socket path
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(p) = s.strip_prefix(EVDEV_PREFIX) {
+            let (path, grab) = match p.strip_suffix(NO_GRAB) {
+                Some(path) => (path, false),
+                None => (p, true),
+            };
+            return match path.is_empty() {
+                true => Err(format!("wrong input '{}', expected evdev://<path>", s)),
+                false => Ok(Source::Evdev {
+                    path: path.to_string(),
+                    grab,
+                }),
+            };
+        }
+        match s.strip_prefix(TCP_PREFIX) {
+            Some(a) => match a.rsplit_once(':') {
+                Some((h, p)) if !h.is_empty() && p.parse::<u16>().is_ok() => {
+                    Ok(Source::Tcp(a.to_string()))
+                }
+                _ => Err(format!("wrong input '{}', expected tcp://host:port", s)),
+            },
+            None if s.is_empty() => Err(String::from("input can't be empty")),
+            None => Ok(Source::Unix(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Unix(p) => write!(f, "{}", p),
+            Source::Tcp(a) => write!(f, "{}{}", TCP_PREFIX, a),
+            Source::Evdev { path, grab: true } => write!(f, "{}{}", EVDEV_PREFIX, path),
+            Source::Evdev { path, grab: false } => {
+                write!(f, "{}{}{}", EVDEV_PREFIX, path, NO_GRAB)
+            }
+        }
+    }
+}
+
+type Reader = Box<dyn Read + Send>;
+type Writer = Box<dyn Write + Send>;
+
+/// Writer of inputs that take no lircd commands
+struct NoCommands;
+
+impl Write for NoCommands {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "the input takes no commands",
+        ))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn connect_tcp(address: &str) -> std::io::Result<TcpStream> {
+    let mut last = None;
+    for a in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, CONNECT_TIMEOUT) {
+            Ok(s) => return Ok(s),
+            Err(e) => last = Some(e),
+        }
+    }
+    let err = || std::io::Error::new(ErrorKind::NotFound, "no address found");
+    Err(last.unwrap_or_else(err))
+}
+
+impl Source {
+    /// Whether every key of the input sends a release, lircd sends them only
+    /// for remotes configured so
+    pub fn sends_releases(&self) -> bool {
+        matches!(self, Source::Evdev { .. })
+    }
+
+    fn connect(&self, read_timeout: Option<Duration>) -> std::io::Result<(Reader, Writer)> {
+        match self {
+            Source::Unix(p) => {
+                let s = UnixStream::connect(p)?;
+                s.set_read_timeout(read_timeout)?;
+                let w = s.try_clone()?;
+                Ok((Box::new(s), Box::new(w)))
+            }
+            Source::Tcp(a) => {
+                let s = connect_tcp(a)?;
+                s.set_read_timeout(read_timeout)?;
+                s.set_nodelay(true)?;
+                let keepalive = TcpKeepalive::new()
+                    .with_time(KEEPALIVE_TIME)
+                    .with_interval(KEEPALIVE_INTERVAL)
+                    .with_retries(KEEPALIVE_RETRIES);
+                SockRef::from(&s).set_tcp_keepalive(&keepalive)?;
+                let w = s.try_clone()?;
+                Ok((Box::new(s), Box::new(w)))
+            }
+            Source::Evdev { path, grab } => {
+                let f = File::open(path)?;
+                let r: Reader = match *grab && is_char_device(path) {
+                    true => Box::new(evdev::Grabbed::new(f)?),
+                    false => Box::new(f),
+                };
+                Ok((r, Box::new(NoCommands)))
+            }
+        }
+    }
+}
+
+/// Passes lines of `reader` to `f`, returns false if `f` stopped it
+fn read_text<F>(reader: Reader, source: &Source, f: &mut F) -> bool
+where
+    F: FnMut(String) -> bool,
+{
+    for line in BufReader::new(reader).lines() {
+        let l = match line {
+            Ok(l) => l,
+            Err(err) => {
+                match err.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                        log::warn!("Nothing read from {} in time", source)
+                    }
+                    _ => log::error!("Can't read from {}: {}", source, err),
+                }
+                return true;
+            }
+        };
+        log::debug!("{}", l);
+        if !f(l) {
+            return false;
+        }
+    }
+    true
+}
+
+fn is_char_device(path: &str) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.file_type().is_char_device())
+}
+
+/// Passes evdev key events of `reader` as lircd lines to `f`, returns false
+/// if `f` stopped it or if a recorded file, not a device, has ended
+fn read_evdev<F>(reader: Reader, source: &Source, device: bool, f: &mut F) -> bool
+where
+    F: FnMut(String) -> bool,
+{
+    match evdev::read_events(BufReader::new(reader), |e| f(e.to_string())) {
+        Ok(true) if !device => {
+            log::info!("End of {}", source);
+            false
+        }
+        Ok(more) => more,
+        Err(err) => {
+            log::error!("Can't read from {}: {}", source, err);
+            true
+        }
+    }
+}
+
+/// Reads lines from the source, reconnecting with `backoff` when it is not
+/// available or closed. The backoff starts again only after a line is read.
+/// A socket with nothing to read for `read_timeout` is dropped too. Evdev
+/// key events are passed as lircd lines. Returns when `f` returns false or
+/// at the end of an evdev input that is a file, not a device.
+/// `connected` gets a writer to every new connection and `None` when it is
+/// lost.
+pub fn read_lines<C, F>(
+    source: &Source,
+    read_timeout: Option<Duration>,
+    backoff: &mut Backoff,
+    mut connected: C,
+    mut f: F,
+) where
+    C: FnMut(Option<Box<dyn Write + Send>>),
+    F: FnMut(String) -> bool,
+{
+    loop {
+        let (reader, writer) = match source.connect(read_timeout) {
+            Ok(c) => c,
+            Err(e) => {
+                let wait = backoff.wait();
+                log::error!(
+                    "Couldn't connect to {}, fail={}: {:?}",
+                    source,
+                    backoff.fails(),
+                    e
+                );
+                log::info!("Waiting {} ms", wait.as_millis());
+                thread::sleep(wait);
+                continue;
+            }
+        };
+        log::info!("Connected to '{}', waiting for messages...", source);
+        connected(Some(writer));
+        let mut read = false;
+        let mut g = |l| {
+            read = true;
+            f(l)
+        };
+        let more = match source {
+            Source::Evdev { path, .. } => read_evdev(reader, source, is_char_device(path), &mut g),
+            _ => read_text(reader, source, &mut g),
+        };
+        if !more {
+            connected(None);
+            return;
+        }
+        connected(None);
+        // a peer that drops every connection before sending is not up yet
+        if read {
+            backoff.reset();
+        }
+        let wait = backoff.wait();
+        log::warn!(
+            "Disconnected from '{}', waiting {} ms",
+            source,
+            wait.as_millis()
+        );
+        thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backoff::Backoff;
+    use crate::evdev::{InputEvent, EV_KEY, EV_SYN, KEY_PRESS, KEY_RELEASE, SYN_REPORT};
+    use crate::input::{read_lines, Source};
+    use std::io::prelude::*;
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn source() {
+        assert_eq!(
+            Source::from_str("/var/run/lirc/lircd"),
+            Ok(Source::Unix(String::from("/var/run/lirc/lircd")))
+        );
+        assert_eq!(
+            Source::from_str("tcp://pi:8765"),
+            Ok(Source::Tcp(String::from("pi:8765")))
+        );
+        assert_eq!(
+            Source::from_str("tcp://[::1]:8765").unwrap().to_string(),
+            "tcp://[::1]:8765"
+        );
+        assert!(Source::from_str("tcp://pi").is_err());
+        assert!(Source::from_str("tcp://:8765").is_err());
+        assert!(Source::from_str("tcp://pi:port").is_err());
+        assert!(Source::from_str("").is_err());
+        assert_eq!(
+            Source::from_str("evdev:///dev/input/event3"),
+            Ok(Source::Evdev {
+                path: String::from("/dev/input/event3"),
+                grab: true
+            })
+        );
+        assert_eq!(
+            Source::from_str("evdev:///dev/input/event3?nograb"),
+            Ok(Source::Evdev {
+                path: String::from("/dev/input/event3"),
+                grab: false
+            })
+        );
+        for s in [
+            "evdev:///dev/input/event3",
+            "evdev:///dev/input/event3?nograb",
+        ] {
+            assert_eq!(Source::from_str(s).unwrap().to_string(), s);
+        }
+        assert!(Source::from_str("evdev://").is_err());
+        assert!(Source::from_str("evdev://?nograb").is_err());
+    }
+
+    fn backoff() -> Backoff {
+        Backoff::new(Duration::from_millis(10), Duration::from_millis(50))
+    }
+
+    #[test]
+    fn tcp() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let source = Source::Tcp(l.local_addr().unwrap().to_string());
+        thread::spawn(move || {
+            let (mut s, _) = l.accept().unwrap();
+            s.write_all(b"0000000000000001 00 KEY_OK d\nlast\n")
+                .unwrap();
+            let mut buf = String::new();
+            std::io::BufReader::new(s).read_line(&mut buf).unwrap();
+            assert_eq!(buf, "VERSION\n");
+        });
+        let mut got = vec![];
+        let mut conns = 0;
+        read_lines(
+            &source,
+            None,
+            &mut backoff(),
+            |w| {
+                if let Some(mut w) = w {
+                    conns += 1;
+                    w.write_all(b"VERSION\n").unwrap();
+                }
+            },
+            |l| {
+                got.push(l);
+                got.len() < 2
+            },
+        );
+        assert_eq!(got, vec!["0000000000000001 00 KEY_OK d", "last"]);
+        assert_eq!(conns, 1);
+    }
+
+    #[test]
+    fn read_timeout() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let source = Source::Tcp(l.local_addr().unwrap().to_string());
+        thread::spawn(move || {
+            // the first peer is silent, the second one answers
+            let (_silent, _) = l.accept().unwrap();
+            let (mut s, _) = l.accept().unwrap();
+            s.write_all(b"line\n").unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+        let now = Instant::now();
+        let mut events = vec![];
+        read_lines(
+            &source,
+            Some(Duration::from_millis(100)),
+            &mut backoff(),
+            |w| events.push(w.is_some()),
+            |_| false,
+        );
+        assert_eq!(events, vec![true, false, true, false]);
+        assert!(now.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn closed_at_once() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let source = Source::Tcp(l.local_addr().unwrap().to_string());
+        thread::spawn(move || {
+            // three peers close at once, the fourth one answers
+            for _ in 0..3 {
+                drop(l.accept().unwrap());
+            }
+            let (mut s, _) = l.accept().unwrap();
+            s.write_all(b"line\n").unwrap();
+            thread::sleep(Duration::from_secs(5));
+        });
+        let now = Instant::now();
+        let mut conns = 0;
+        let mut b = backoff();
+        read_lines(
+            &source,
+            None,
+            &mut b,
+            |w| conns += w.is_some() as u32,
+            |_| false,
+        );
+        assert_eq!(conns, 4);
+        // 10 + 20 + 40 ms of waits between the reconnects
+        assert!(now.elapsed() >= Duration::from_millis(70));
+        assert_eq!(b.fails(), 3);
+    }
+
+    #[test]
+    fn evdev() {
+        let path = std::env::temp_dir().join(format!("changer-evdev-{}", std::process::id()));
+        let mut data = vec![];
+        for (code, value) in [(0x160, KEY_PRESS), (0x160, KEY_RELEASE), (103, KEY_PRESS)] {
+            data.extend(InputEvent::new(EV_KEY, code, value).to_bytes());
+            data.extend(InputEvent::new(EV_SYN, SYN_REPORT, 0).to_bytes());
+        }
+        std::fs::write(&path, data).unwrap();
+        let source = Source::Evdev {
+            path: path.to_string_lossy().to_string(),
+            grab: true,
+        };
+        let mut got = vec![];
+        let mut conns = vec![];
+        read_lines(
+            &source,
+            None,
+            &mut backoff(),
+            |w| {
+                if let Some(mut w) = w {
+                    conns.push(w.write_all(b"VERSION\n").is_err());
+                }
+            },
+            |l| {
+                got.push(l);
+                true
+            },
+        );
+        std::fs::remove_file(&path).unwrap();
+        // a recorded file is not grabbed and is read once
+        assert_eq!(
+            got,
+            vec![
+                "0000000000000160 00 KEY_OK devinput",
+                "0000000000000160 00 KEY_OK_UP devinput",
+                "0000000000000067 00 KEY_UP devinput",
+            ]
+        );
+        assert_eq!(conns, vec![true]);
+    }
+}
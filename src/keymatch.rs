@@ -0,0 +1,107 @@
+/// Keys a rule applies to, a `None` field matches anything
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct KeyMatch {
+    /// `None` applies in every mode
+    pub mode: Option<String>,
+    pub device: Option<String>,
+    pub name: Option<String>,
+    /// `device` and `name` match in any case, as lircrc remotes and buttons do
+    pub ignore_case: bool,
+}
+
+impl KeyMatch {
+    /// Matches `name` in any mode and on any device
+    pub fn name(name: &str) -> Self {
+        KeyMatch {
+            name: Some(name.to_string()),
+            ..KeyMatch::default()
+        }
+    }
+
+    pub fn matches(&self, mode: &str, device: &str, name: &str) -> bool {
+        self.mode.as_ref().is_none_or(|m| m == mode)
+            && self.device.as_ref().is_none_or(|d| self.is(d, device))
+            && self.name.as_ref().is_none_or(|n| self.is(n, name))
+    }
+
+    fn is(&self, want: &str, got: &str) -> bool {
+        match self.ignore_case {
+            true => want.eq_ignore_ascii_case(got),
+            false => want == got,
+        }
+    }
+
+    pub fn specificity(&self) -> u8 {
+        u8::from(self.mode.is_some()) * 4
+            + u8::from(self.name.is_some()) * 2
+            + u8::from(self.device.is_some())
+    }
+}
+
+/// Returns the most specific of `items` for the key in `mode`: ones of the
+/// mode go first, then device and name, name only, device only. Ties go to
+/// the first one.
+pub fn find<'a, T, F>(items: &'a [T], key: F, mode: &str, device: &str, name: &str) -> Option<&'a T>
+where
+    F: Fn(&T) -> &KeyMatch,
+{
+    let mut res: Option<&T> = None;
+    for i in items.iter().filter(|i| key(i).matches(mode, device, name)) {
+        if res.is_none_or(|p| key(i).specificity() > key(p).specificity()) {
+            res = Some(i);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::keymatch::{find, KeyMatch};
+
+    fn km(mode: Option<&str>, device: Option<&str>, name: Option<&str>) -> KeyMatch {
+        KeyMatch {
+            mode: mode.map(String::from),
+            device: device.map(String::from),
+            name: name.map(String::from),
+            ignore_case: false,
+        }
+    }
+
+    #[test]
+    fn matches() {
+        let k = KeyMatch::name("KEY_OK");
+        assert!(k.matches("default", "d", "KEY_OK"));
+        assert!(!k.matches("default", "d", "key_ok"));
+        assert!(!k.matches("default", "d", "KEY_UP"));
+        let k = KeyMatch {
+            ignore_case: true,
+            ..km(Some("tv"), Some("mce"), Some("key_ok"))
+        };
+        assert!(k.matches("tv", "mce", "KEY_OK"));
+        assert!(k.matches("tv", "MCE", "Key_Ok"));
+        assert!(!k.matches("default", "mce", "KEY_OK"));
+        assert!(!k.matches("tv", "d", "KEY_OK"));
+        assert!(km(None, None, None).matches("tv", "d", "A"));
+    }
+
+    #[test]
+    fn most_specific() {
+        let items = vec![
+            km(None, Some("d"), None),
+            km(None, None, Some("A")),
+            km(None, Some("d"), Some("A")),
+            km(Some("tv"), Some("d"), None),
+            km(None, Some("d"), Some("A")),
+        ];
+        let f = |mode, device, name| {
+            let k = find(&items, |k| k, mode, device, name)?;
+            items.iter().position(|i| std::ptr::eq(i, k))
+        };
+        // the first of equal ones
+        assert_eq!(f("default", "d", "A"), Some(2));
+        assert_eq!(f("default", "e", "A"), Some(1));
+        assert_eq!(f("default", "d", "B"), Some(0));
+        assert_eq!(f("tv", "d", "A"), Some(3));
+        assert_eq!(f("default", "e", "B"), None);
+    }
+}
@@ -0,0 +1,602 @@
+use crate::config::SettingsReader;
+use crate::event::Event;
+use crate::keymatch::KeyMatch;
+use crate::mode::Mode;
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+const HTTP_PREFIX: &str = "http://";
+const TCP_PREFIX: &str = "tcp://";
+const DEFAULT_PATH: &str = "/jsonrpc";
+
+/// Where Kodi takes JSON-RPC calls
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Endpoint {
+    /// `host:port` and path of Kodi's web server
+    Http { address: String, path: String },
+    /// `host:port` of the raw JSON-RPC port, 9090 on Kodi
+    Tcp(String),
+}
+
+fn check_address(a: &str) -> bool {
+    a.rsplit_once(':')
+        .is_some_and(|(h, p)| !h.is_empty() && p.parse::<u16>().is_ok())
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    /// Parses `http://host:port/jsonrpc` or `tcp://host:port`, the path is
+    /// `/jsonrpc` if not set
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(a) = s.strip_prefix(TCP_PREFIX) {
+            return match check_address(a) {
+                true => Ok(Endpoint::Tcp(a.to_string())),
+                false => Err(format!("wrong url '{}', expected tcp://host:port", s)),
+            };
+        }
+        let rest = s
+            .strip_prefix(HTTP_PREFIX)
+            .ok_or_else(|| format!("wrong url '{}', expected http:// or tcp://", s))?;
+        let (address, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, DEFAULT_PATH),
+        };
+        if !check_address(address) {
+            return Err(format!("wrong url '{}', expected http://host:port/path", s));
+        }
+        Ok(Endpoint::Http {
+            address: address.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Http { address, path } => write!(f, "{}{}{}", HTTP_PREFIX, address, path),
+            Endpoint::Tcp(a) => write!(f, "{}{}", TCP_PREFIX, a),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Error {
+    /// Kodi can't be reached, the call is not sent and is worth a retry
+    Unsent(String),
+    /// the answer can't be read, the call is not tried again as Kodi may
+    /// have done it
+    Transport(String),
+    /// the web server refused the call, e.g. a wrong password
+    Status(String),
+    /// Kodi answered with a JSON-RPC error
+    Rpc { code: i64, message: String },
+}
+
+impl Error {
+    fn retry(&self) -> bool {
+        matches!(self, Error::Unsent(_))
+    }
+
+    fn unsent(e: std::io::Error) -> Self {
+        Error::Unsent(e.to_string())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Unsent(s) | Error::Transport(s) => write!(f, "{}", s),
+            Error::Status(s) => write!(f, "web server answered '{}'", s),
+            Error::Rpc { code, message } => write!(f, "{} ({})", message, code),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Transport(e.to_string())
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(data: &[u8]) -> String {
+    let mut res = String::new();
+    for c in data.chunks(3) {
+        let n = c
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= c.len() {
+                true => res.push(char::from(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize])),
+                false => res.push('='),
+            }
+        }
+    }
+    res
+}
+
+fn connect(address: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last = None;
+    for a in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&a, timeout) {
+            Ok(s) => {
+                s.set_read_timeout(Some(timeout))?;
+                s.set_write_timeout(Some(timeout))?;
+                return Ok(s);
+            }
+            Err(e) => last = Some(e),
+        }
+    }
+    let err = || std::io::Error::new(std::io::ErrorKind::NotFound, "no address found");
+    Err(last.unwrap_or_else(err))
+}
+
+/// Result of a JSON-RPC answer
+fn result(answer: Value) -> Result<Value, Error> {
+    match answer.get("error") {
+        Some(e) => Err(Error::Rpc {
+            code: e.get("code").and_then(Value::as_i64).unwrap_or(0),
+            message: e
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        }),
+        None => Ok(answer.get("result").cloned().unwrap_or(Value::Null)),
+    }
+}
+
+/// JSON-RPC client of Kodi, a connection per call
+pub struct Client {
+    endpoint: Endpoint,
+    /// `Authorization` header of HTTP calls
+    auth: Option<String>,
+    timeout: Duration,
+    retries: u32,
+    retry_wait: Duration,
+    id: u64,
+}
+
+impl Client {
+    pub fn new(endpoint: Endpoint, timeout: Duration) -> Self {
+        Client {
+            endpoint,
+            auth: None,
+            timeout,
+            retries: 0,
+            retry_wait: Duration::ZERO,
+            id: 0,
+        }
+    }
+
+    /// Sets the user of Kodi's web server
+    pub fn with_auth(mut self, user: &str, password: &str) -> Self {
+        self.auth = Some(base64(format!("{}:{}", user, password).as_bytes()));
+        self
+    }
+
+    /// Calls failing to reach Kodi are tried again `retries` times
+    pub fn with_retries(mut self, retries: u32, wait: Duration) -> Self {
+        self.retries = retries;
+        self.retry_wait = wait;
+        self
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Calls `method` once, `Value::Null` params are not sent
+    pub fn call(&mut self, method: &str, params: &Value) -> Result<Value, Error> {
+        self.id += 1;
+        let mut req = json!({"jsonrpc": "2.0", "method": method, "id": self.id});
+        if !params.is_null() {
+            req["params"] = params.clone();
+        }
+        let body = req.to_string();
+        log::debug!("Kodi call {}", body);
+        let answer = match &self.endpoint {
+            Endpoint::Http { address, path } => self.post(address, path, &body)?,
+            Endpoint::Tcp(address) => self.send(address, &body)?,
+        };
+        result(answer)
+    }
+
+    /// Calls `method`, trying it again if Kodi is not reachable. A call is
+    /// never sent twice.
+    pub fn call_retry(&mut self, method: &str, params: &Value) -> Result<Value, Error> {
+        let mut tries = 0;
+        loop {
+            match self.call(method, params) {
+                Err(e) if e.retry() && tries < self.retries => {
+                    tries += 1;
+                    log::warn!("Kodi call {} failed, retry {}: {}", method, tries, e);
+                    thread::sleep(self.retry_wait);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn post(&self, address: &str, path: &str, body: &str) -> Result<Value, Error> {
+        let mut s = connect(address, self.timeout).map_err(Error::unsent)?;
+        // HTTP/1.0 for a plain body ended by the closed connection
+        let mut req = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n\
+            Content-Length: {}\r\n",
+            path,
+            address,
+            body.len()
+        );
+        if let Some(a) = &self.auth {
+            req += &format!("Authorization: Basic {}\r\n", a);
+        }
+        req += "\r\n";
+        req += body;
+        s.write_all(req.as_bytes()).map_err(Error::unsent)?;
+        let mut data = vec![];
+        s.read_to_end(&mut data)?;
+        let data = String::from_utf8_lossy(&data);
+        let (head, body) = data
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| Error::Transport(String::from("incomplete HTTP answer")))?;
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(Error::Status(status.to_string()));
+        }
+        serde_json::from_str(body).map_err(|e| Error::Transport(format!("wrong answer: {}", e)))
+    }
+
+    /// Sends the call to the raw port, skipping notifications until the
+    /// answer comes
+    fn send(&self, address: &str, body: &str) -> Result<Value, Error> {
+        let mut s = connect(address, self.timeout).map_err(Error::unsent)?;
+        s.write_all(body.as_bytes()).map_err(Error::unsent)?;
+        let answers = serde_json::Deserializer::from_reader(BufReader::new(s)).into_iter();
+        for a in answers {
+            let a: Value = a.map_err(|e| Error::Transport(format!("wrong answer: {}", e)))?;
+            if a.get("id") == Some(&json!(self.id)) {
+                return Ok(a);
+            }
+            log::debug!("Kodi notification {}", a);
+        }
+        Err(Error::Transport(String::from("connection closed")))
+    }
+}
+
+/// JSON-RPC call made on an event
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Call {
+    pub key: KeyMatch,
+    /// call on repeats too, on the first event of a press only if false
+    pub repeat: bool,
+    pub method: String,
+    /// `Value::Null` sends no params
+    pub params: Value,
+}
+
+impl Call {
+    fn matches(&self, mode: &str, e: &Event) -> bool {
+        self.key.matches(mode, &e.device, &e.name) && (self.repeat || e.repeat == 0)
+    }
+}
+
+/// Makes Kodi calls on events sent to clients. The calls are made one by
+/// one in their own thread, in the order of the events.
+pub struct Kodi {
+    calls: Vec<Call>,
+    mode: Mode,
+    jobs: Sender<(String, Value)>,
+}
+
+fn serve(mut client: Client, jobs: Receiver<(String, Value)>) {
+    for (method, params) in jobs {
+        match client.call_retry(&method, &params) {
+            Ok(res) => log::info!("Kodi {}: {}", method, res),
+            Err(err) => log::error!("Kodi call {} to {}: {}", method, client.endpoint(), err),
+        }
+    }
+}
+
+impl Kodi {
+    pub fn new(client: Client, calls: Vec<Call>, mode: Mode) -> Self {
+        let (jobs, r_jobs) = unbounded();
+        thread::spawn(move || serve(client, r_jobs));
+        Kodi { calls, mode, jobs }
+    }
+
+    /// Queues the calls matching the event, returns how many were queued
+    pub fn start(&self, e: &Event) -> usize {
+        let mut res = 0;
+        if e.end {
+            return res;
+        }
+        let mode = self.mode.get();
+        for c in self.calls.iter().filter(|c| c.matches(&mode, e)) {
+            if let Err(err) = self.jobs.send((c.method.clone(), c.params.clone())) {
+                log::warn!("{}", err);
+                continue;
+            }
+            res += 1;
+        }
+        res
+    }
+
+    /// Passes events from `data` to `out` making calls on them, until `data`
+    /// or `cl` is closed
+    pub fn run(
+        mut self,
+        data: Receiver<Event>,
+        out: Sender<Event>,
+        mut settings: SettingsReader,
+        cl: Receiver<u32>,
+    ) {
+        loop {
+            select! {
+                recv(data) -> msg => {
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Some(s) = settings.changed() {
+                        log::info!("update kodi calls");
+                        self.calls = s.kodi_calls.clone();
+                    }
+                    self.start(&received);
+                    out.send(received).unwrap();
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in kodi");
+                    break;
+                }
+            }
+        }
+        log::info!("exit kodi");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::Event;
+    use crate::keymatch::KeyMatch;
+    use crate::kodi::{base64, Call, Client, Endpoint, Error, Kodi};
+    use crate::mode::Mode;
+    use serde_json::{json, Value};
+    use std::io::prelude::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+
+    const OK: &str = r#"{"id":1,"jsonrpc":"2.0","result":"OK"}"#;
+
+    /// Stand-in Kodi answering a connection with every answer in turn, an
+    /// empty one closes the connection. Returns the requests it read.
+    fn kodi(http: bool, answers: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = l.local_addr().unwrap().to_string();
+        let h = thread::spawn(move || {
+            let mut res = vec![];
+            for a in answers {
+                let (mut s, _) = l.accept().unwrap();
+                let mut r = BufReader::new(s.try_clone().unwrap());
+                let mut req = String::new();
+                if http {
+                    let mut len = 0;
+                    loop {
+                        let mut line = String::new();
+                        r.read_line(&mut line).unwrap();
+                        if let Some(v) = line.strip_prefix("Content-Length:") {
+                            len = v.trim().parse().unwrap();
+                        }
+                        req += &line;
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let mut body = vec![0; len];
+                    r.read_exact(&mut body).unwrap();
+                    req += &String::from_utf8(body).unwrap();
+                } else {
+                    let mut values = serde_json::Deserializer::from_reader(r).into_iter::<Value>();
+                    req = values.next().unwrap().unwrap().to_string();
+                }
+                res.push(req);
+                s.write_all(a.as_bytes()).unwrap();
+            }
+            res
+        });
+        (address, h)
+    }
+
+    fn http_answer(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+
+    fn client(url: &str) -> Client {
+        Client::new(Endpoint::from_str(url).unwrap(), Duration::from_secs(2))
+            .with_retries(2, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn endpoint() {
+        assert_eq!(
+            Endpoint::from_str("http://kodi:8080"),
+            Ok(Endpoint::Http {
+                address: String::from("kodi:8080"),
+                path: String::from("/jsonrpc")
+            })
+        );
+        assert_eq!(
+            Endpoint::from_str("http://[::1]:80/rpc")
+                .unwrap()
+                .to_string(),
+            "http://[::1]:80/rpc"
+        );
+        assert_eq!(
+            Endpoint::from_str("tcp://kodi:9090"),
+            Ok(Endpoint::Tcp(String::from("kodi:9090")))
+        );
+        assert!(Endpoint::from_str("tcp://kodi").is_err());
+        assert!(Endpoint::from_str("http://kodi/jsonrpc").is_err());
+        assert!(Endpoint::from_str("https://kodi:443").is_err());
+    }
+
+    #[test]
+    fn base64_encode() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"k"), "aw==");
+        assert_eq!(base64(b"ko"), "a28=");
+        assert_eq!(base64(b"kodi:kodi"), "a29kaTprb2Rp");
+    }
+
+    #[test]
+    fn http() {
+        let (address, h) = kodi(true, vec![http_answer("200 OK", OK)]);
+        let mut c = client(&format!("http://{}/jsonrpc", address)).with_auth("kodi", "kodi");
+        let params = json!({"playerid": 1, "value": {"seconds": 30}});
+        assert_eq!(c.call_retry("Player.Seek", &params), Ok(json!("OK")));
+        let req = h.join().unwrap().remove(0);
+        assert!(req.starts_with("POST /jsonrpc HTTP/1.0\r\n"));
+        assert!(req.contains("\r\nAuthorization: Basic a29kaTprb2Rp\r\n"));
+        let body: Value = serde_json::from_str(req.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({"jsonrpc": "2.0", "id": 1, "method": "Player.Seek", "params": params})
+        );
+    }
+
+    #[test]
+    fn http_errors() {
+        let answer = r#"{"error":{"code":-32602,"message":"Invalid params."},"id":1}"#;
+        let (address, h) = kodi(
+            true,
+            vec![
+                http_answer("200 OK", answer),
+                http_answer("401 Unauthorized", ""),
+            ],
+        );
+        let mut c = client(&format!("http://{}", address));
+        assert_eq!(
+            c.call_retry("Player.Seek", &Value::Null),
+            Err(Error::Rpc {
+                code: -32602,
+                message: String::from("Invalid params.")
+            })
+        );
+        // neither is tried again
+        assert_eq!(
+            c.call_retry("Input.Home", &Value::Null),
+            Err(Error::Status(String::from("HTTP/1.1 401 Unauthorized")))
+        );
+        let reqs = h.join().unwrap();
+        assert_eq!(reqs.len(), 2);
+        assert!(!reqs[1].contains("params"));
+    }
+
+    #[test]
+    fn tcp() {
+        let notification = r#"{"jsonrpc":"2.0","method":"Player.OnPause","params":{}}"#;
+        let (address, h) = kodi(false, vec![format!("{}\n{}", notification, OK)]);
+        let mut c = client(&format!("tcp://{}", address));
+        assert_eq!(c.call_retry("Input.Select", &Value::Null), Ok(json!("OK")));
+        assert_eq!(
+            h.join().unwrap(),
+            vec![r#"{"id":1,"jsonrpc":"2.0","method":"Input.Select"}"#]
+        );
+    }
+
+    #[test]
+    fn retry() {
+        // a port Kodi starts listening on a bit later
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let h = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let l = TcpListener::bind(address).unwrap();
+            let (mut s, _) = l.accept().unwrap();
+            let r = BufReader::new(s.try_clone().unwrap());
+            let mut values = serde_json::Deserializer::from_reader(r).into_iter::<Value>();
+            let req = values.next().unwrap().unwrap();
+            let answer = json!({"id": req["id"], "jsonrpc": "2.0", "result": "OK"});
+            s.write_all(answer.to_string().as_bytes()).unwrap();
+        });
+        let mut c =
+            client(&format!("tcp://{}", address)).with_retries(20, Duration::from_millis(20));
+        assert_eq!(c.call_retry("Input.Select", &Value::Null), Ok(json!("OK")));
+        h.join().unwrap();
+
+        let mut c = client("tcp://127.0.0.1:1").with_retries(1, Duration::ZERO);
+        assert!(matches!(
+            c.call_retry("Input.Select", &Value::Null),
+            Err(Error::Unsent(_))
+        ));
+    }
+
+    #[test]
+    fn no_retry_after_send() {
+        // the connection is closed after the call is read
+        let answer = r#"{"id":2,"jsonrpc":"2.0","result":"OK"}"#;
+        let (address, h) = kodi(false, vec![String::new(), answer.to_string()]);
+        let mut c = client(&format!("tcp://{}", address));
+        assert!(matches!(
+            c.call_retry("Input.Select", &Value::Null),
+            Err(Error::Transport(_))
+        ));
+        assert_eq!(c.call_retry("Input.Back", &Value::Null), Ok(json!("OK")));
+        let reqs = h.join().unwrap();
+        assert!(reqs[0].contains("Input.Select"));
+        assert!(reqs[1].contains("Input.Back"));
+    }
+
+    #[test]
+    fn calls() {
+        let (address, h) = kodi(false, vec![OK.to_string()]);
+        let call = Call {
+            key: KeyMatch {
+                device: Some(String::from("mce")),
+                ..KeyMatch::name("KEY_RIGHT_HOLD")
+            },
+            repeat: false,
+            method: String::from("Player.Seek"),
+            params: json!({"playerid": 1, "value": {"seconds": 30}}),
+        };
+        let mode = Mode::default();
+        let mut tv = call.clone();
+        tv.key.mode = Some(String::from("tv"));
+        tv.method = String::from("Input.Home");
+        let k = Kodi::new(client(&format!("tcp://{}", address)), vec![call, tv], mode);
+        let ev = |s: &str| Event::from_str(s).unwrap();
+        assert_eq!(k.start(&ev("1 1 KEY_RIGHT_HOLD mce")), 0);
+        assert_eq!(k.start(&ev("1 0 KEY_RIGHT_HOLD d")), 0);
+        assert_eq!(k.start(&ev("1 0 KEY_RIGHT mce")), 0);
+        assert_eq!(k.start(&ev("1 0 KEY_RIGHT_HOLD mce")), 1);
+        let req: Value = serde_json::from_str(&h.join().unwrap()[0]).unwrap();
+        assert_eq!(req["method"], "Player.Seek");
+        assert_eq!(req["params"]["value"]["seconds"], 30);
+    }
+}
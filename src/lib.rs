@@ -0,0 +1,27 @@
+//! LIRC event changer: reads lircd or evdev events, makes HOLD, multi-tap and
+//! sequence events and serves them on lircd compatible unix and TCP sockets.
+//! Client commands are proxied to lircd.
+
+pub mod action;
+pub mod backoff;
+pub mod cidr;
+pub mod config;
+pub mod control;
+pub mod evdev;
+pub mod event;
+pub mod eventserver;
+pub mod hold;
+pub mod input;
+pub mod keycodes;
+pub mod keymatch;
+pub mod kodi;
+pub mod lircrc;
+pub mod logger;
+pub mod mode;
+pub mod packet;
+pub mod proxy;
+pub mod remap;
+pub mod sequence;
+pub mod server;
+pub mod stats;
+pub mod uinput;
@@ -0,0 +1,370 @@
+use std::fmt;
+
+/// `flags` values lirc knows
+pub const FLAGS: [&str; 5] = ["once", "quit", "mode", "startup_mode", "toggle_reset"];
+pub const ONCE: &str = "once";
+pub const QUIT: &str = "quit";
+pub const STARTUP_MODE: &str = "startup_mode";
+
+/// One `begin ... end` block of a lircrc file
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Entry {
+    pub prog: String,
+    /// `None` for `*`
+    pub remote: Option<String>,
+    /// `None` for `*`, may be a changer name like `KEY_OK_HOLD`. Several
+    /// buttons make a sequence, none is only valid with `startup_mode`.
+    pub buttons: Vec<Option<String>>,
+    /// commands run in turn on every press
+    pub configs: Vec<String>,
+    /// 0 - ignore repeats, n - act on every n-th repeat
+    pub repeat: u32,
+    /// repeats ignored after the first event of a press
+    pub delay: u32,
+    /// mode to switch to
+    pub mode: Option<String>,
+    pub flags: Vec<String>,
+    /// `begin <mode> ... end <mode>` block the entry is in
+    pub in_mode: Option<String>,
+    /// line of `begin`
+    pub line: usize,
+}
+
+impl Entry {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn wildcard(v: &str) -> Option<String> {
+    match v {
+        "*" => None,
+        _ => Some(v.to_string()),
+    }
+}
+
+/// Parses a lircrc file, returns all the errors found
+pub fn parse(data: &str) -> Result<Vec<Entry>, Vec<ParseError>> {
+    let mut res = vec![];
+    let mut errors = vec![];
+    let mut entry: Option<Entry> = None;
+    let mut mode: Option<(String, usize)> = None;
+    // the current entry has a prog line
+    let mut has_prog = false;
+    for (i, l) in data.lines().enumerate() {
+        let n = i + 1;
+        let mut err = |msg: String| errors.push(ParseError { line: n, msg });
+        let l = l.trim();
+        if l.is_empty() || l.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match l.split_once('=') {
+            Some((k, v)) => (k.trim(), Some(v.trim())),
+            None => (l, None),
+        };
+        let words = key.split_whitespace().collect::<Vec<_>>();
+        match (words.as_slice(), value) {
+            (["begin"], None) => {
+                if entry.is_some() {
+                    err(String::from("begin inside of a begin/end block"));
+                    continue;
+                }
+                entry = Some(Entry {
+                    in_mode: mode.as_ref().map(|(m, _)| m.clone()),
+                    line: n,
+                    ..Entry::default()
+                });
+                has_prog = false;
+            }
+            (["begin", m], None) => {
+                if entry.is_some() {
+                    err(format!("mode '{}' inside of a begin/end block", m));
+                } else if let Some((p, _)) = &mode {
+                    err(format!("mode '{}' inside of mode '{}'", m, p));
+                } else {
+                    mode = Some((m.to_string(), n));
+                }
+            }
+            (["end"], None) => match entry.take() {
+                Some(e) => {
+                    if !has_prog {
+                        err(String::from("prog is missing"));
+                    } else if e.buttons.is_empty() && !e.has_flag(STARTUP_MODE) {
+                        err(String::from("button is missing"));
+                    } else if e.buttons.len() > 1 && e.buttons.contains(&None) {
+                        err(String::from("button * can't be in a sequence"));
+                    } else if e.has_flag(STARTUP_MODE) && e.mode.is_none() {
+                        err(String::from("startup_mode without mode"));
+                    } else {
+                        res.push(e);
+                    }
+                }
+                None => err(String::from("end without begin")),
+            },
+            (["end", m], None) => {
+                if entry.is_some() {
+                    err(format!("end of mode '{}' inside of a begin/end block", m));
+                    continue;
+                }
+                match mode.take() {
+                    Some((p, _)) if p == *m => {}
+                    Some((p, l)) => {
+                        err(format!("end of mode '{}' inside of mode '{}'", m, p));
+                        mode = Some((p, l));
+                    }
+                    None => err(format!("end of mode '{}' without begin", m)),
+                }
+            }
+            (["include", ..], None) => err(String::from("include is not supported")),
+            ([k], Some(v)) => {
+                let e = match entry.as_mut() {
+                    Some(e) => e,
+                    None => {
+                        err(format!("'{}' outside of a begin/end block", k));
+                        continue;
+                    }
+                };
+                if v.is_empty() && *k != "config" {
+                    err(format!("{} can't be empty", k));
+                    continue;
+                }
+                match *k {
+                    "prog" => {
+                        e.prog = v.to_string();
+                        has_prog = true;
+                    }
+                    "remote" => e.remote = wildcard(v),
+                    "button" => e.buttons.push(wildcard(v)),
+                    "config" => e.configs.push(v.to_string()),
+                    "repeat" | "delay" => match v.parse::<u32>() {
+                        Ok(r) if *k == "repeat" => e.repeat = r,
+                        Ok(r) => e.delay = r,
+                        Err(_) => err(format!("can't parse {} '{}'", k, v)),
+                    },
+                    "mode" => e.mode = Some(v.to_string()),
+                    "flags" => {
+                        for f in v.split('|').map(str::trim) {
+                            if FLAGS.contains(&f) {
+                                e.flags.push(f.to_string());
+                            } else {
+                                err(format!("unknown flag '{}'", f));
+                            }
+                        }
+                    }
+                    _ => err(format!("unknown key '{}'", k)),
+                }
+            }
+            _ => err(format!("can't parse '{}'", l)),
+        }
+    }
+    if let Some(e) = entry {
+        errors.push(ParseError {
+            line: e.line,
+            msg: String::from("begin without end"),
+        });
+    }
+    if let Some((m, line)) = mode {
+        errors.push(ParseError {
+            line,
+            msg: format!("mode '{}' without end", m),
+        });
+    }
+    match errors.is_empty() {
+        true => Ok(res),
+        false => Err(errors),
+    }
+}
+
+/// Reads and parses a lircrc file, errors are joined into one message
+pub fn load(path: &str) -> Result<Vec<Entry>, String> {
+    let data =
+        std::fs::read_to_string(path).map_err(|e| format!("can't read '{}': {}", path, e))?;
+    parse(&data).map_err(|errs| {
+        errs.iter()
+            .map(|e| format!("{}: {}", path, e))
+            .collect::<Vec<_>>()
+            .join("; ")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lircrc::{parse, Entry, ParseError, ONCE, QUIT, STARTUP_MODE};
+
+    fn errors(data: &str) -> Vec<String> {
+        parse(data)
+            .unwrap_err()
+            .iter()
+            .map(ParseError::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn entries() {
+        let res = parse(
+            "# comment\n\
+            begin\n  prog = irexec\n  button = KEY_POWER_HOLD\n  config = echo off\nend\n\
+            \n\
+            begin\n\tprog=irexec\n\tremote=*\n\tbutton=KEY_UP\n\tconfig=echo a = b\n\
+            \trepeat=2\n\tdelay=3\n\tflags = quit | once\nend\n",
+        )
+        .unwrap();
+        assert_eq!(
+            res,
+            vec![
+                Entry {
+                    prog: String::from("irexec"),
+                    remote: None,
+                    buttons: vec![Some(String::from("KEY_POWER_HOLD"))],
+                    configs: vec![String::from("echo off")],
+                    line: 2,
+                    ..Entry::default()
+                },
+                Entry {
+                    prog: String::from("irexec"),
+                    remote: None,
+                    buttons: vec![Some(String::from("KEY_UP"))],
+                    configs: vec![String::from("echo a = b")],
+                    repeat: 2,
+                    delay: 3,
+                    flags: vec![String::from("quit"), String::from("once")],
+                    line: 8,
+                    ..Entry::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn modes() {
+        let res = parse(
+            "begin\nprog = irexec\nbutton = KEY_MENU_HOLD\nmode = tv\nend\n\
+            begin tv\n\
+            begin\nprog = irexec\nremote = apple\nbutton = *\nconfig = x\nend\n\
+            end tv\n",
+        )
+        .unwrap();
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].mode, Some(String::from("tv")));
+        assert_eq!(res[0].in_mode, None);
+        assert!(res[0].configs.is_empty());
+        assert_eq!(res[1].in_mode, Some(String::from("tv")));
+        assert_eq!(res[1].remote, Some(String::from("apple")));
+        assert_eq!(res[1].buttons, vec![None]);
+    }
+
+    #[test]
+    fn missing() {
+        assert_eq!(
+            errors("begin\nbutton = A\nend\nbegin\nprog = p\nend"),
+            vec!["line 3: prog is missing", "line 6: button is missing"]
+        );
+        assert_eq!(
+            errors("begin\nprog = p\nbutton = A\n"),
+            vec!["line 1: begin without end"]
+        );
+        assert_eq!(
+            errors("begin\nprog = p\nflags = startup_mode\nend"),
+            vec!["line 4: startup_mode without mode"]
+        );
+        assert_eq!(
+            errors("begin\nprog = p\nbutton = A\nbutton = *\nend"),
+            vec!["line 5: button * can't be in a sequence"]
+        );
+        assert_eq!(errors("begin tv\n"), vec!["line 1: mode 'tv' without end"]);
+    }
+
+    #[test]
+    fn bad_blocks() {
+        assert_eq!(
+            errors("end\nprog = p\nbegin\nbegin\nprog = p\nbutton = A\nend\nend tv"),
+            vec![
+                "line 1: end without begin",
+                "line 2: 'prog' outside of a begin/end block",
+                "line 4: begin inside of a begin/end block",
+                "line 8: end of mode 'tv' without begin",
+            ]
+        );
+        assert_eq!(
+            errors("begin tv\nbegin radio\nend radio\nend tv"),
+            vec![
+                "line 2: mode 'radio' inside of mode 'tv'",
+                "line 3: end of mode 'radio' inside of mode 'tv'",
+            ]
+        );
+    }
+
+    #[test]
+    fn irexec() {
+        // a lircrc as irexec and lirc's own examples write it
+        let res = parse(
+            "begin\n\
+            \tprog   = irexec\n\
+            \tmode   = tv\n\
+            \tflags  = startup_mode\n\
+            end\n\
+            \n\
+            begin tv\n\
+            \tbegin\n\
+            \t\tprog   = irexec\n\
+            \t\tremote = mceusb\n\
+            \t\tbutton = key_power\n\
+            \t\tconfig = echo on\n\
+            \t\tconfig = echo off\n\
+            \tend\n\
+            \tbegin\n\
+            \t\tprog   = irexec\n\
+            \t\tbutton = KEY_1\n\
+            \t\tbutton = KEY_2\n\
+            \t\tconfig = echo 12\n\
+            \t\tflags  = quit|once\n\
+            \tend\n\
+            end tv\n",
+        )
+        .unwrap();
+        assert_eq!(res.len(), 3);
+        assert!(res[0].buttons.is_empty());
+        assert_eq!(res[0].mode, Some(String::from("tv")));
+        assert!(res[0].has_flag(STARTUP_MODE));
+        assert_eq!(res[1].buttons, vec![Some(String::from("key_power"))]);
+        assert_eq!(res[1].configs, vec!["echo on", "echo off"]);
+        assert_eq!(res[1].in_mode, Some(String::from("tv")));
+        assert_eq!(
+            res[2].buttons,
+            vec![Some(String::from("KEY_1")), Some(String::from("KEY_2"))]
+        );
+        assert!(res[2].has_flag(QUIT) && res[2].has_flag(ONCE));
+    }
+
+    #[test]
+    fn bad_values() {
+        assert_eq!(
+            errors(
+                "begin\nprog = p\nbutton = A\n\
+                repeat = x\nflags = quit|often\nsize = 1\nbutton =\nwhat\nend\ninclude ~/.lircrc2"
+            ),
+            vec![
+                "line 4: can't parse repeat 'x'",
+                "line 5: unknown flag 'often'",
+                "line 6: unknown key 'size'",
+                "line 7: button can't be empty",
+                "line 8: can't parse 'what'",
+                "line 10: include is not supported",
+            ]
+        );
+    }
+}
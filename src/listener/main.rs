@@ -1,7 +1,6 @@
 use clap::{App, Arg};
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::os::unix::net::UnixStream;
+use lirc_changer_rust::backoff::Backoff;
+use lirc_changer_rust::input::{read_lines, Source};
 use std::sync::mpsc::channel;
 use std::thread;
 use std::time::Duration;
@@ -17,54 +16,27 @@ fn main() {
                 .short('i')
                 .long("input")
                 .value_name("FILE")
-                .help("Sets a socket in path")
+                .help("Sets a socket in path or tcp://host:port")
                 .takes_value(true),
         )
         .get_matches();
     log::info!("Starting listener");
 
     let in_path = matches.value_of("socketIn").unwrap_or("test");
-    let in_path_s: String = in_path.into();
+    let source = match in_path.parse::<Source>() {
+        Ok(s) => s,
+        Err(err) => {
+            log::error!("{}, reading it as a socket path", err);
+            Source::Unix(in_path.to_string())
+        }
+    };
 
     thread::spawn(move || {
-        let mut fail_count = 0;
-        loop {
-            log::info!("Try connect");
-            let socket = match UnixStream::connect(in_path_s.clone()) {
-                Ok(sock) => sock,
-                Err(e) => {
-                    fail_count += 1;
-                    log::error!(
-                        "Couldn't connect to {}, fail={}: {:?}",
-                        in_path_s, fail_count, e
-                    );
-                    let mut wait_time = 500 + u64::pow(fail_count, 2) * 100;
-                    if wait_time > 5000 {
-                        wait_time = 5000;
-                    }
-                    log::info!("Waiting {} ms", wait_time);
-                    thread::sleep(Duration::from_millis(wait_time));
-                    continue;
-                }
-            };
-            fail_count = 0;
-            log::info!("Connected to '{}', waiting for messages...", in_path_s);
-            let stream = BufReader::new(socket);
-
-            stream
-                .lines()
-                .map(|l| {
-                    log::info!("Read");
-                    l.unwrap()
-                })
-                .map(String::from)
-                .map(|l| {
-                    log::info!("GOT: {}", l);
-                    l
-                })
-                .count();
-                log::info!("Exit socket stream");
-        }
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_millis(5000));
+        read_lines(&source, None, &mut backoff, |_| {}, |l| {
+            log::info!("GOT: {}", l);
+            true
+        });
     });
 
     let (tx, rx) = channel();
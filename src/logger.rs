@@ -0,0 +1,61 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// set once the level is changed at runtime, `RUST_LOG` filters are not
+/// used any more then
+static OVERRIDDEN: AtomicBool = AtomicBool::new(false);
+
+/// env_logger whose level can be changed at runtime
+struct Logger {
+    /// configured by `RUST_LOG`
+    env: env_logger::Logger,
+    /// passes everything, `log::max_level` filters records
+    all: env_logger::Logger,
+}
+
+impl Logger {
+    fn current(&self) -> &env_logger::Logger {
+        match OVERRIDDEN.load(Ordering::Relaxed) {
+            true => &self.all,
+            false => &self.env,
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && self.current().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= log::max_level() {
+            self.current().log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.current().flush()
+    }
+}
+
+/// Sets up logging from `RUST_LOG` as `env_logger::init` does
+pub fn init() {
+    let env = env_logger::Builder::from_default_env().build();
+    let all = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    log::set_max_level(env.filter());
+    if let Err(err) = log::set_boxed_logger(Box::new(Logger { env, all })) {
+        eprintln!("can't init logger: {}", err);
+    }
+}
+
+/// Changes the level of all modules
+pub fn set_level(level: &str) -> Result<LevelFilter, String> {
+    let l = level
+        .parse::<LevelFilter>()
+        .map_err(|_| format!("wrong log level '{}'", level))?;
+    OVERRIDDEN.store(true, Ordering::Relaxed);
+    log::set_max_level(l);
+    Ok(l)
+}
@@ -1,27 +1,19 @@
-mod event;
-
 use clap::{App, Arg};
-use crossbeam_channel::never;
-use crossbeam_channel::{select, tick, unbounded};
+use crossbeam_channel::unbounded;
+use lirc_changer_rust::{
+    action, backoff, config, control, event, eventserver, hold, input, kodi, lircrc, logger, mode,
+    packet, proxy, remap, sequence, server, stats, uinput,
+};
 use signal_hook::{
     consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM},
     iterator::Signals,
 };
-use std::collections::HashMap;
-use std::io::prelude::*;
-use std::io::BufReader;
-use std::os::unix::net::{UnixListener, UnixStream};
-use std::path::Path;
 use std::process::ExitCode;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
-use std::time::Instant;
 
 fn main() -> ExitCode {
-    env_logger::init();
+    logger::init();
     let matches = App::new("changer")
         .version("0.1")
         .author("Airenas V.<airenass@gmail.com>")
@@ -30,9 +22,10 @@ fn main() -> ExitCode {
             Arg::new("socketIn")
                 .short('i')
                 .long("input")
-                .value_name("FILE")
-                .help("Sets a socket in path")
-                .takes_value(true),
+                .value_name("FILE|URL")
+                .help("Sets a lircd socket path or tcp://host:port, may be repeated")
+                .takes_value(true)
+                .multiple_occurrences(true),
         )
         .arg(
             Arg::new("socketOut")
@@ -42,35 +35,231 @@ fn main() -> ExitCode {
                 .help("Sets a socket output path")
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("socketControl")
+                .long("control")
+                .value_name("FILE")
+                .help("Sets a control socket path, no control socket by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("Sets a TOML config file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("holdMs")
+                .long("hold-ms")
+                .value_name("MS")
+                .help("Sets a HOLD threshold in ms")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("tickMs")
+                .long("tick-ms")
+                .value_name("MS")
+                .help("Sets a repeat gap in ms after which a press is finished")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("idleMs")
+                .long("idle-ms")
+                .value_name("MS")
+                .help("Sets an initial timer interval in ms")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("reconnectMinMs")
+                .long("reconnect-min-ms")
+                .value_name("MS")
+                .help("Sets a first wait in ms before reconnecting to the input socket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("reconnectMaxMs")
+                .long("reconnect-max-ms")
+                .value_name("MS")
+                .help("Sets a max wait in ms before reconnecting to the input socket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("readTimeoutMs")
+                .long("read-timeout-ms")
+                .value_name("MS")
+                .help("Reconnects if nothing is read from the input for so long")
+                .takes_value(true),
+        )
+        .subcommand(
+            App::new("check-lircrc")
+                .about("Checks a lircrc file and reports errors with line numbers")
+                .arg(
+                    Arg::new("file")
+                        .value_name("FILE")
+                        .help("Sets a lircrc file path")
+                        .required(true),
+                ),
+        )
         .get_matches();
+    if let Some(m) = matches.subcommand_matches("check-lircrc") {
+        return check_lircrc(m.value_of("file").unwrap());
+    }
     log::info!("Starting IR eChanger");
 
-    let in_path = matches
-        .value_of("socketIn")
-        .unwrap_or("/var/run/lirc/lircd");
-    let out_path = matches
+    let sources = match matches
+        .values_of("socketIn")
+        .map_or(vec!["/var/run/lirc/lircd"], |v| v.collect())
+        .into_iter()
+        .map(str::parse::<input::Source>)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let out_path: String = matches
         .value_of("socketOut")
-        .unwrap_or("/var/run/lirc/lircd2");
-    let socket = match UnixStream::connect(in_path) {
-        Ok(sock) => sock,
+        .unwrap_or("/var/run/lirc/lircd2")
+        .into();
+    let control_path: Option<String> = matches.value_of("socketControl").map(String::from);
+    let file_config = match read_file_config(&matches) {
+        Ok(fc) => fc,
         Err(e) => {
-            log::error!("Couldn't connect to {}: {:?}", in_path, e);
+            log::error!("{}", e);
             return ExitCode::FAILURE;
         }
     };
+    let settings = match read_settings(&matches, &file_config) {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Wrong config: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let backoff = match read_backoff(&matches, &file_config) {
+        Ok(b) => b,
+        Err(e) => {
+            log::error!("Wrong config: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let read_timeout =
+        match parse_ms(&matches, "readTimeoutMs").and_then(|ms| file_config.read_timeout(ms)) {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!("Wrong config: {}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+    let tcp_outputs = match file_config.tcp_outputs() {
+        Ok(t) => t,
+        Err(e) => {
+            log::error!("Wrong config: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let eventserver_output = match file_config.eventserver_output() {
+        Ok(e) => e,
+        Err(e) => {
+            log::error!("Wrong config: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let kodi_client = match file_config.kodi_client() {
+        Ok(k) => k,
+        Err(e) => {
+            log::error!("Wrong config: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    log::info!(
+        "Hold: {} ms, tick: {} ms, idle: {} ms",
+        settings.hold.hold.as_millis(),
+        settings.hold.tick.as_millis(),
+        settings.hold.idle.as_millis()
+    );
 
-    log::info!("Connected to '{}', waiting for messages...", in_path);
-    let stream = BufReader::new(socket);
-
-    if Path::new(out_path).exists() {
-        std::fs::remove_file(out_path).unwrap();
-    }
-    let listener = UnixListener::bind(out_path).unwrap();
+    let keyboard = match file_config.uinput_output() {
+        Some(u) => match uinput::Device::create(&u) {
+            Ok(d) => {
+                log::info!("Created uinput keyboard '{}'", u.name);
+                Some(d)
+            }
+            Err(e) => {
+                log::error!("Can't create uinput keyboard at '{}': {}", u.path, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let event_server = match eventserver_output {
+        Some(out) => match eventserver::EventServer::connect(&out) {
+            Ok(es) => {
+                log::info!("Sending buttons to Kodi event server {}", out.address);
+                Some(es)
+            }
+            Err(e) => {
+                log::error!("Can't connect to Kodi event server {}: {}", out.address, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let listener = server::bind(&out_path).unwrap();
     log::info!("Connected to '{}', waiting for clients...", out_path);
+    let mut tcp_listeners = vec![];
+    for t in tcp_outputs {
+        match std::net::TcpListener::bind(t.address) {
+            Ok(l) => {
+                log::info!("Listening on tcp {}", t.address);
+                if t.allow.is_empty() {
+                    log::warn!("tcp {} allows no clients", t.address);
+                }
+                tcp_listeners.push((l, t.allow));
+            }
+            Err(e) => {
+                log::error!("Can't listen on tcp {}: {}", t.address, e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let control_listener = match &control_path {
+        Some(p) => match server::bind(p) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                log::error!("Can't bind control socket '{}': {}", p, e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
 
     let (tx, rx) = unbounded();
     let (ptx, prx) = unbounded();
+    let (otx, orx) = unbounded();
+    let mode = mode::Mode::default();
+    if let Some(m) = &settings.startup_mode {
+        mode.set(m);
+    }
+    let (stx, srx) = unbounded();
     let (rtx, rrx) = unbounded();
+    let (t_reload, r_reload) = unbounded();
+    let (mtx, mrx) = unbounded();
+    let (atx, arx) = unbounded();
+    let (t_cmd, r_cmd) = unbounded();
+    let upstream = Arc::new(proxy::Upstream::new());
+    let inputs = Arc::new(stats::Inputs::new(sources.iter().map(|s| s.to_string())));
+    let releases = sources
+        .iter()
+        .enumerate()
+        .filter_map(|(i, s)| s.sends_releases().then_some(i))
+        .collect::<Vec<_>>();
+    let stats = Arc::new(stats::Stats::default());
+    let started = std::time::Instant::now();
 
     let (t_close, r_close) = unbounded();
     let (t_close_main, r_close_main): (
@@ -82,37 +271,83 @@ fn main() -> ExitCode {
             log::debug!("Got main exit event {}", sig);
             drop(t_close);
             return sig;
-        }    
+        }
         0
     });
 
-    let mut signals = Signals::new(&[SIGINT, SIGHUP, SIGTERM, SIGQUIT]).unwrap();
+    let mut signals = Signals::new([SIGINT, SIGHUP, SIGTERM, SIGQUIT]).unwrap();
     let tclm = t_close_main.clone();
+    let t_reload_sig = t_reload.clone();
     thread::spawn(move || {
         for (num, sig) in signals.forever().enumerate() {
             log::debug!("Received signal {:?}, {} time(s)", sig, num);
+            if sig == SIGHUP {
+                log::info!("Got SIGHUP, reloading config");
+                if let Err(err) = t_reload_sig.send(None) {
+                    log::warn!("{}", err);
+                }
+                continue;
+            }
             tclm.send(sig).unwrap();
         }
     });
 
-    thread::spawn(move || {
-        stream
-            .lines()
-            .map(|l| l.unwrap())
-            .map(|l| {
-                log::debug!("{}", l);
-                l
-            })
-            .map(|l| event::Event::from_str(&l))
-            .filter_map(|r| r.map_err(|e| log::error!("{}", e)).ok())
-            .for_each(|l| tx.send(l).unwrap())
-    });
+    let sinks = InputSinks {
+        events: tx.clone(),
+        packets: stx.clone(),
+        clients: rtx.clone(),
+        upstream: Some(upstream.clone()),
+        reload: t_reload.clone(),
+        stats: stats.clone(),
+        inputs: inputs.clone(),
+    };
+    for (i, source) in sources.into_iter().enumerate() {
+        // client commands go to the first input only
+        let sinks = InputSinks {
+            upstream: sinks.upstream.clone().filter(|_| i == 0),
+            ..sinks.clone()
+        };
+        let backoff = backoff.clone();
+        thread::spawn(move || read_input(source, i, read_timeout, sinks, backoff));
+    }
+    let rtxc = rtx.clone();
+    let upc = upstream.clone();
+    let statsc = stats.clone();
+    thread::spawn(move || forward_commands(r_cmd, upc, rtxc, statsc));
+    let start = Start {
+        file_config: file_config.clone(),
+        startup_mode: settings.startup_mode.clone(),
+    };
+    let shared = config::SharedSettings::new(settings);
+
+    if let Some(l) = control_listener {
+        let c = Arc::new(control::Control {
+            mode: mode.clone(),
+            events: ptx.clone(),
+            inject: tx,
+            clients: rtx.clone(),
+            inputs,
+            reload: t_reload,
+            stats,
+            started,
+        });
+        thread::spawn(move || control::serve(l, c));
+    }
 
     let mut threads = vec![];
 
     let r_close_cl = r_close.clone();
+    let sr = shared.reader();
+    let modec = mode.clone();
+    // the event server holds buttons down until their press ends
+    let ends = event_server.is_some();
     threads.push(thread::spawn(move || {
-        process(rx, ptx, r_close_cl);
+        let s = sr.get();
+        hold::HoldDetector::new(s.hold, s.rules.clone())
+            .with_mode(modec)
+            .with_releases(releases)
+            .with_ends(ends)
+            .run(rx, ptx, sr, r_close_cl);
         match t_close_main.send(2) {
             Ok(_) => {}
             Err(err) => {
@@ -121,208 +356,306 @@ fn main() -> ExitCode {
         };
     }));
 
-    let rtxc = rtx.clone();
-    threads.push(thread::spawn(move || broadcast(prx, rrx, rtxc, r_close)));
+    let r_close_cl = r_close.clone();
+    let sr = shared.reader();
+    let modec = mode.clone();
+    threads.push(thread::spawn(move || {
+        mode::Modes::new(sr.get().modes.clone(), modec).run(prx, otx, sr, r_close_cl)
+    }));
 
-    let mut num = 0;
+    let r_close_cl = r_close.clone();
+    let sr = shared.reader();
+    threads.push(thread::spawn(move || {
+        sequence::Sequences::new(sr.get().sequences.clone()).run(orx, mtx, sr, r_close_cl)
+    }));
 
-    thread::spawn(move || {
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let rtxc = rtx.clone();
-                    num += 1;
-                    thread::spawn(move || handle_client(stream, rtxc, num));
-                }
-                Err(err) => {
-                    log::error!("Error: {}", err);
-                    break;
-                }
-            }
-        }
-    });
+    // remapped events go to the keyboard and Kodi before the actions
+    let mut remapped = atx;
+    if let Some(es) = event_server {
+        let (etx, erx) = unbounded();
+        let r_close_cl = r_close.clone();
+        threads.push(thread::spawn(move || es.run(erx, remapped, r_close_cl)));
+        remapped = etx;
+    }
+    if let Some(client) = kodi_client {
+        log::info!("Calling Kodi at {}", client.endpoint());
+        let (ktx, krx) = unbounded();
+        let r_close_cl = r_close.clone();
+        let sr = shared.reader();
+        let k = kodi::Kodi::new(client, sr.get().kodi_calls.clone(), mode.clone());
+        threads.push(thread::spawn(move || k.run(krx, remapped, sr, r_close_cl)));
+        remapped = ktx;
+    }
+    if let Some(device) = keyboard {
+        let (ktx, krx) = unbounded();
+        let r_close_cl = r_close.clone();
+        let sr = shared.reader();
+        let keys = sr.get().keymap.clone();
+        threads.push(thread::spawn(move || {
+            uinput::Keyboard::new(device, keys).run(krx, remapped, sr, r_close_cl)
+        }));
+        remapped = ktx;
+    }
+
+    let r_close_cl = r_close.clone();
+    let sr = shared.reader();
+    let modec = mode.clone();
+    threads.push(thread::spawn(move || {
+        remap::Remaps::new(sr.get().remaps.clone(), modec).run(mrx, remapped, sr, r_close_cl)
+    }));
+
+    let r_close_cl = r_close.clone();
+    let sr = shared.reader();
+    threads.push(thread::spawn(move || {
+        let s = sr.get();
+        action::Actions::new(s.actions.clone(), s.max_actions, mode).run(arx, stx, sr, r_close_cl)
+    }));
+    // every stage has its reader before the first reload
+    thread::spawn(move || reload(matches, start, r_reload, shared));
+
+    let rtxc = rtx.clone();
+    threads.push(thread::spawn(move || {
+        server::broadcast(srx, rrx, rtxc, r_close)
+    }));
+
+    let ids = Arc::new(server::Ids::default());
+    for (l, allow) in tcp_listeners {
+        let (rtxc, t_cmdc, idsc) = (rtx.clone(), t_cmd.clone(), ids.clone());
+        thread::spawn(move || server::accept_tcp(l, allow, rtxc, Some(t_cmdc), idsc));
+    }
+    thread::spawn(move || server::accept(listener, rtx, Some(t_cmd), ids));
 
     threads.into_iter().for_each(|h| h.join().unwrap());
     let ec = t_exit.join().unwrap();
 
     log::info!("drop pipe file '{}'", out_path);
-    std::fs::remove_file(out_path).unwrap();
+    std::fs::remove_file(&out_path).unwrap();
+    if let Some(p) = control_path {
+        log::info!("drop control file '{}'", p);
+        if let Err(e) = std::fs::remove_file(&p) {
+            log::warn!("{}", e);
+        }
+    }
 
     log::info!("Bye!");
     ExitCode::from(u8::try_from(ec).unwrap())
 }
 
-enum Msg {
-    Init(u32, Sender<String>),
-    Close(u32),
-}
-
-fn handle_client(mut stream: UnixStream, info: crossbeam_channel::Sender<Msg>, num: u32) {
-    log::info!("connected {}", num);
-    let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
-    info.send(Msg::Init(num, tx)).unwrap();
-    for received in rx {
-        log::debug!("Got: {}", &received);
-        match stream.write_all((received.clone() + "\n").as_bytes()) {
-            Ok(_) => {
-                log::debug!("Wrote: {}", &received);
-            }
-            Err(err) => {
-                log::warn!("Can't write to {}. {}", num, err);
-                break;
+/// Prints lircrc errors as `<file>:<line>: <error>`
+fn check_lircrc(path: &str) -> ExitCode {
+    let data = match std::fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match lircrc::parse(&data) {
+        Ok(entries) => {
+            println!("{}: {} entries, OK", path, entries.len());
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            for e in errors {
+                println!("{}:{}: {}", path, e.line, e.msg);
             }
+            ExitCode::FAILURE
         }
     }
-    log::info!("disconnected {}", num);
+}
 
-    match info.send(Msg::Close(num)) {
-        Ok(_) => {}
-        Err(err) => {
-            log::warn!("{}", err);
-        }
+fn read_file_config(matches: &clap::ArgMatches) -> Result<config::FileConfig, String> {
+    match matches.value_of("config") {
+        Some(path) => config::FileConfig::load(path),
+        None => Ok(config::FileConfig::default()),
     }
 }
 
-fn process(
-    data: crossbeam_channel::Receiver<event::Event>,
-    out: crossbeam_channel::Sender<event::Event>,
-    cl: crossbeam_channel::Receiver<u32>,
-) {
-    let mut prev: Option<event::Event> = None;
-    let mut at = Instant::now();
-    fn show(dur: Duration) {
-        log::debug!("Elapsed: {}.{:03} sec", dur.as_secs(), dur.subsec_millis());
-    }
+fn read_settings(
+    matches: &clap::ArgMatches,
+    file_config: &config::FileConfig,
+) -> Result<config::Settings, String> {
+    file_config.settings(read_hold_config(matches, file_config)?)
+}
 
-    let start = Instant::now();
-    let mut update = tick(Duration::from_secs(1));
+/// Config the changer started with, for what can't be reloaded
+struct Start {
+    file_config: config::FileConfig,
+    startup_mode: Option<String>,
+}
 
-    loop {
-        select! {
-            recv(update) -> _ => {
-                log::debug!("on timer");
-                show(start.elapsed());
-                match prev {
-                    None => {
-                    }
-                    Some(e) => {
-                        let now = Instant::now();
-                        if now > at + Duration::from_millis(500) {
-                            out.send(e.to_hold()).unwrap();
-                        } else{
-                            out.send(e.to_new()).unwrap();
-                        }
-                        prev = None;
-                    }
+/// Re-reads the config on every `signal`, the old one stays on errors.
+/// The result goes to the reply channel if the signal has one, with a
+/// warning for every changed setting that is used at start only.
+fn reload(
+    matches: clap::ArgMatches,
+    start: Start,
+    signal: crossbeam_channel::Receiver<Option<control::ReloadReply>>,
+    shared: config::SharedSettings,
+) {
+    for reply in signal {
+        let res = match read_file_config(&matches)
+            .and_then(|fc| read_settings(&matches, &fc).map(|s| (fc, s)))
+        {
+            Ok((fc, settings)) => {
+                let mut fixed = fc.fixed_changes(&start.file_config);
+                if settings.startup_mode != start.startup_mode {
+                    fixed.push("lircrc startup_mode");
                 }
-                update = never()
-            }
-            recv(data) -> msg => {
-                show(start.elapsed());
-                let received = match msg {
-                    Ok(msg) => msg,
-                    Err(err) => {
-                        log::warn!("{}", err);
-                        break;
-                    }
-                };
-                log::debug!("Got process {}", received.to_str());
-                let now = Instant::now();
-                match prev {
-                    None => {
-                        log::debug!("none");
-                        if received.repeat == 0 {
-                            prev = Some(received);
-                            at = now;
-                        }
-                    }
-                    Some(e) => {
-                        prev = None;
-                        if e.name != received.name {
-                            log::debug!("!=name");
-                            out.send(e).unwrap();
-                            prev = Some(received);
-                            at = now;
-                        } else if e.repeat + 1 != received.repeat {
-                            log::debug!("!=repeat");
-                            out.send(e).unwrap();
-                            if received.repeat == 0 {
-                                prev = Some(received);
-                                at = now;
-                            }
-                        } else if now > at + Duration::from_millis(500) {
-                            log::debug!("long");
-                            out.send(e.to_hold()).unwrap();
-                        } else{
-                            log::debug!("skip");
-                            prev = Some(received)
-                        }
-                    }
+                shared.set(settings);
+                log::info!("Config reloaded");
+                let warnings = fixed
+                    .into_iter()
+                    .map(|name| format!("{} is used at start only, restart to apply it", name))
+                    .collect::<Vec<_>>();
+                for w in &warnings {
+                    log::warn!("{}", w);
                 }
-                update = tick(Duration::from_millis(100));
+                Ok(warnings)
             }
-            recv(cl) -> _ => {
-                log::debug!("event from close channel in process");
-                break;
+            Err(e) => {
+                log::error!("Can't reload config, keeping the old one: {}", e);
+                Err(e)
+            }
+        };
+        if let Some(r) = reply {
+            if let Err(err) = r.send(res) {
+                log::warn!("{}", err);
             }
         }
     }
-    log::info!("exit process");
 }
 
-fn broadcast(
-    data: crossbeam_channel::Receiver<event::Event>,
-    info: crossbeam_channel::Receiver<Msg>,
-    close_info: crossbeam_channel::Sender<Msg>,
-    cl: crossbeam_channel::Receiver<u32>,
+fn read_hold_config(
+    matches: &clap::ArgMatches,
+    file_config: &config::FileConfig,
+) -> Result<config::HoldConfig, String> {
+    file_config.hold_config(
+        parse_ms(matches, "holdMs")?,
+        parse_ms(matches, "tickMs")?,
+        parse_ms(matches, "idleMs")?,
+    )
+}
+
+fn read_backoff(
+    matches: &clap::ArgMatches,
+    file_config: &config::FileConfig,
+) -> Result<backoff::Backoff, String> {
+    file_config.backoff(
+        parse_ms(matches, "reconnectMinMs")?,
+        parse_ms(matches, "reconnectMaxMs")?,
+    )
+}
+
+fn parse_ms(matches: &clap::ArgMatches, name: &str) -> Result<Option<u64>, String> {
+    match matches.value_of(name) {
+        Some(v) => v
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|e| format!("can't parse {} '{}': {}", name, v, e)),
+        None => Ok(None),
+    }
+}
+
+/// Where `read_input` sends what lircd writes
+#[derive(Clone)]
+struct InputSinks {
+    events: crossbeam_channel::Sender<event::Event>,
+    /// packets passed to clients as they are
+    packets: crossbeam_channel::Sender<packet::Packet>,
+    clients: crossbeam_channel::Sender<server::Msg>,
+    /// `None` if client commands don't go to this input
+    upstream: Option<Arc<proxy::Upstream>>,
+    reload: crossbeam_channel::Sender<Option<control::ReloadReply>>,
+    stats: Arc<stats::Stats>,
+    inputs: Arc<stats::Inputs>,
+}
+
+/// Reads input number `index`, its events are tagged with it
+fn read_input(
+    source: input::Source,
+    index: usize,
+    read_timeout: Option<std::time::Duration>,
+    sinks: InputSinks,
+    mut backoff: backoff::Backoff,
 ) {
-    let receivers: HashMap<u32, Sender<String>> = HashMap::new();
-    let l_receivers = Arc::new(Mutex::new(receivers));
-    let rc = l_receivers.clone();
-    thread::spawn(move || {
-        for received in data {
-            let s = received.to_str();
-            log::debug!("Got {}", &s);
-            let lr = rc.lock().unwrap();
-            for (key, value) in lr.iter() {
-                let s = s.clone();
-                match value.send(s) {
-                    Ok(_) => {
-                        log::debug!("send {}", key);
-                    }
-                    Err(err) => {
-                        log::error!("Can't send to {}. {}", key, err);
-                        close_info.send(Msg::Close(*key)).unwrap();
-                    }
+    // a block cut by a reconnect is dropped
+    let reader = std::cell::RefCell::new(packet::PacketReader::new());
+    let s = &sinks;
+    let connected = |w: Option<Box<dyn std::io::Write + Send>>| {
+        reader.borrow_mut().reset();
+        s.inputs.set(index, w.is_some());
+        match (w, &s.upstream) {
+            (Some(w), u) => {
+                stats::Stats::inc(&s.stats.connects);
+                if let Some(u) = u {
+                    u.connect(w);
                 }
             }
+            (None, Some(u)) => {
+                for (id, r) in u.disconnect() {
+                    send_to(&s.clients, id, r);
+                }
+            }
+            (None, None) => {}
         }
-    });
-
-    loop {
-        select! {
-            recv(info) -> msg => {
-                let received = msg.unwrap();
-                match received {
-                    Msg::Init(id, stream) => {
-                        log::info!("Got init: {}", id);
-                        let mut lr = l_receivers.lock().unwrap();
-                        lr.insert(id, stream);
-                        log::info!("Clients: {}", lr.len());
-                    }
-                    Msg::Close(id) => {
-                        log::info!("Got close: {}", id);
-                        let mut lr = l_receivers.lock().unwrap();
-                        lr.remove(&id);
-                        log::info!("Clients: {}", lr.len());
+    };
+    input::read_lines(
+        &source,
+        read_timeout,
+        &mut backoff,
+        connected,
+        |l| match reader.borrow_mut().push(&l) {
+            None => true,
+            Some(Ok(packet::Packet::Event(mut e))) => {
+                stats::Stats::inc(&s.stats.events);
+                e.source = index;
+                s.events.send(e).is_ok()
+            }
+            Some(Ok(packet::Packet::Reply(r))) => {
+                match s.upstream.as_ref().and_then(|u| u.reply(&r)) {
+                    Some(id) => {
+                        send_to(&s.clients, id, r);
+                        true
                     }
+                    None => s.packets.send(packet::Packet::Reply(r)).is_ok(),
                 }
             }
-            recv(cl) -> _ => {
-                log::debug!("event from close channel in broadcast");
-                break;
+            Some(Ok(packet::Packet::Sighup)) => {
+                log::info!("lircd got SIGHUP");
+                if let Err(err) = s.reload.send(None) {
+                    log::warn!("{}", err);
+                }
+                s.packets.send(packet::Packet::Sighup).is_ok()
             }
+            Some(Err(err)) => {
+                stats::Stats::inc(&s.stats.parse_errors);
+                log::error!("Can't parse '{}': {}", l, err);
+                true
+            }
+        },
+    );
+    log::info!("exit input reader");
+}
+
+/// Passes client commands to lircd, failures are answered right away
+fn forward_commands(
+    commands: crossbeam_channel::Receiver<(u32, String)>,
+    upstream: Arc<proxy::Upstream>,
+    clients: crossbeam_channel::Sender<server::Msg>,
+    stats: Arc<stats::Stats>,
+) {
+    for (id, line) in commands {
+        log::info!("Client {} sends '{}'", id, line);
+        stats::Stats::inc(&stats.commands);
+        if let Err(r) = upstream.send(id, &line) {
+            send_to(&clients, id, r);
         }
     }
-    log::info!("exit broadcast");
+}
+
+fn send_to(clients: &crossbeam_channel::Sender<server::Msg>, id: u32, r: packet::Reply) {
+    if let Err(err) = clients.send(server::Msg::Send(id, r.to_string())) {
+        log::warn!("{}", err);
+    }
 }
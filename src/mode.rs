@@ -0,0 +1,242 @@
+use crate::config::SettingsReader;
+use crate::event::Event;
+use crate::keymatch::{self, KeyMatch};
+use crossbeam_channel::{select, Receiver, Sender};
+use std::sync::{Arc, RwLock};
+
+pub const DEFAULT_MODE: &str = "default";
+/// device of the events announcing a new mode
+pub const MODE_DEVICE: &str = "changer";
+
+/// Active mode shared by the pipeline stages
+#[derive(Debug, Clone)]
+pub struct Mode(Arc<RwLock<String>>);
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode(Arc::new(RwLock::new(String::from(DEFAULT_MODE))))
+    }
+}
+
+impl Mode {
+    pub fn get(&self) -> String {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Returns true if the mode changed
+    pub fn set(&self, mode: &str) -> bool {
+        let mut m = self.0.write().unwrap();
+        if *m == mode {
+            return false;
+        }
+        log::info!("mode {} -> {}", m, mode);
+        *m = mode.to_string();
+        true
+    }
+}
+
+/// Checks a mode name can be sent in a lircd line
+pub fn check_name(mode: &str) -> Result<(), String> {
+    if mode.is_empty() || mode.contains(char::is_whitespace) {
+        return Err(format!("wrong mode name '{}'", mode));
+    }
+    Ok(())
+}
+
+/// Event telling clients about the new mode: `MODE_<mode> changer`
+pub fn announce(mode: &str) -> Event {
+    Event {
+        code: 0,
+        repeat: 0,
+        name: format!("MODE_{}", mode),
+        device: String::from(MODE_DEVICE),
+        source: 0,
+        end: false,
+    }
+}
+
+/// A key switching to `mode`. As in lircrc, the key only enters `mode`,
+/// another switch leaves it, unless `toggle` is set.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ModeSwitch {
+    /// the switching key, its `mode` is the mode the switch works in
+    pub key: KeyMatch,
+    pub mode: String,
+    /// the key switches back to the default mode if `mode` is active
+    pub toggle: bool,
+}
+
+/// Swallows mode switching keys and announces the new mode instead
+pub struct Modes {
+    switches: Vec<ModeSwitch>,
+    mode: Mode,
+}
+
+impl Modes {
+    pub fn new(switches: Vec<ModeSwitch>, mode: Mode) -> Self {
+        Modes { switches, mode }
+    }
+
+    /// Returns the event to pass further: the same one, a mode announcement
+    /// or `None` for repeats of a switching key and switches to the active
+    /// mode
+    pub fn push(&self, e: Event) -> Option<Event> {
+        if e.end {
+            return Some(e);
+        }
+        let active = self.mode.get();
+        // a switch of the active mode goes first
+        let s = match keymatch::find(&self.switches, |s| &s.key, &active, &e.device, &e.name) {
+            Some(s) => s,
+            None => return Some(e),
+        };
+        if e.repeat > 0 {
+            return None;
+        }
+        let to = match s.toggle && active == s.mode {
+            true => DEFAULT_MODE,
+            false => &s.mode,
+        };
+        self.mode.set(to).then(|| announce(to))
+    }
+
+    /// Reads events from `data` until it or `cl` is closed
+    pub fn run<T: From<Event>>(
+        mut self,
+        data: Receiver<Event>,
+        out: Sender<T>,
+        mut settings: SettingsReader,
+        cl: Receiver<u32>,
+    ) {
+        loop {
+            select! {
+                recv(data) -> msg => {
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Some(s) = settings.changed() {
+                        log::info!("update modes");
+                        self.switches = s.modes.clone();
+                    }
+                    if let Some(e) = self.push(received) {
+                        out.send(e.into()).unwrap();
+                    }
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in modes");
+                    break;
+                }
+            }
+        }
+        log::info!("exit modes");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::Event;
+    use crate::keymatch::KeyMatch;
+    use crate::mode::{announce, check_name, Mode, ModeSwitch, Modes, DEFAULT_MODE};
+    use std::str::FromStr;
+
+    fn ev(line: &str) -> Event {
+        Event::from_str(line).unwrap()
+    }
+
+    fn switch(key: &str, in_mode: Option<&str>, mode: &str) -> ModeSwitch {
+        ModeSwitch {
+            key: KeyMatch {
+                mode: in_mode.map(String::from),
+                ..KeyMatch::name(key)
+            },
+            mode: String::from(mode),
+            toggle: false,
+        }
+    }
+
+    #[test]
+    fn enter() {
+        let m = Mode::default();
+        let modes = Modes::new(
+            vec![
+                switch("KEY_RED", None, "tv"),
+                switch("KEY_BLUE", None, DEFAULT_MODE),
+            ],
+            m.clone(),
+        );
+        assert_eq!(modes.push(ev("1 0 KEY_RED d")), Some(announce("tv")));
+        // pressed again it stays in the mode, as in lircrc
+        assert_eq!(modes.push(ev("1 0 KEY_RED d")), None);
+        assert_eq!(m.get(), "tv");
+        assert_eq!(
+            modes.push(ev("1 0 KEY_BLUE d")),
+            Some(announce(DEFAULT_MODE))
+        );
+        assert_eq!(m.get(), DEFAULT_MODE);
+    }
+
+    #[test]
+    fn mode() {
+        let m = Mode::default();
+        assert_eq!(m.get(), DEFAULT_MODE);
+        assert!(m.set("tv"));
+        assert!(!m.set("tv"));
+        assert_eq!(m.clone().get(), "tv");
+    }
+
+    #[test]
+    fn names() {
+        assert!(check_name("tv").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("t v").is_err());
+        assert_eq!(
+            announce("tv").to_string(),
+            "0000000000000000 00 MODE_tv changer"
+        );
+    }
+
+    #[test]
+    fn toggle() {
+        let m = Mode::default();
+        let s = ModeSwitch {
+            toggle: true,
+            ..switch("KEY_MENU_HOLD", None, "tv")
+        };
+        let modes = Modes::new(vec![s], m.clone());
+        let e = ev("1 0 KEY_OK d");
+        assert_eq!(modes.push(e.clone()), Some(e));
+        assert_eq!(modes.push(ev("1 0 KEY_MENU_HOLD d")), Some(announce("tv")));
+        assert_eq!(m.get(), "tv");
+        assert_eq!(modes.push(ev("1 1 KEY_MENU_HOLD d")), None);
+        assert_eq!(
+            modes.push(ev("1 0 KEY_MENU_HOLD d")),
+            Some(announce(DEFAULT_MODE))
+        );
+        assert_eq!(m.get(), DEFAULT_MODE);
+    }
+
+    #[test]
+    fn in_mode() {
+        let m = Mode::default();
+        let modes = Modes::new(
+            vec![
+                switch("KEY_RED", None, "tv"),
+                switch("KEY_RED", Some("tv"), "radio"),
+                switch("KEY_BLUE", Some("radio"), DEFAULT_MODE),
+            ],
+            m.clone(),
+        );
+        let blue = ev("1 0 KEY_BLUE d");
+        assert_eq!(modes.push(blue.clone()), Some(blue));
+        modes.push(ev("1 0 KEY_RED d"));
+        assert_eq!(m.get(), "tv");
+        modes.push(ev("1 0 KEY_RED d"));
+        assert_eq!(m.get(), "radio");
+        modes.push(ev("1 0 KEY_BLUE d"));
+        assert_eq!(m.get(), DEFAULT_MODE);
+    }
+}
@@ -0,0 +1,267 @@
+use crate::event::{Event, EventParseError};
+use std::fmt;
+
+/// Max lines in a `BEGIN ... END` block, `LIST` of a big remote fits
+pub const MAX_BLOCK_LINES: usize = 4096;
+
+/// lircd reply to a command, e.g. `VERSION` or `LIST`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Reply {
+    pub command: String,
+    pub success: bool,
+    pub data: Vec<String>,
+}
+
+/// Anything lircd writes to its socket
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Packet {
+    Event(Event),
+    Reply(Reply),
+    /// lircd re-read its config
+    Sighup,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PacketError {
+    Event(EventParseError),
+    Block(String),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::Event(e) => write!(f, "{}", e),
+            PacketError::Block(s) => write!(f, "wrong reply block: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+impl From<Event> for Packet {
+    fn from(e: Event) -> Self {
+        Packet::Event(e)
+    }
+}
+
+impl fmt::Display for Reply {
+    /// Writes the reply as lircd does, without the last newline
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BEGIN\n{}\n", self.command)?;
+        writeln!(f, "{}", if self.success { "SUCCESS" } else { "ERROR" })?;
+        if !self.data.is_empty() {
+            write!(f, "DATA\n{}\n", self.data.len())?;
+            for l in &self.data {
+                writeln!(f, "{}", l)?;
+            }
+        }
+        write!(f, "END")
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Packet::Event(e) => write!(f, "{}", e),
+            Packet::Reply(r) => write!(f, "{}", r),
+            Packet::Sighup => write!(f, "BEGIN\nSIGHUP\nEND"),
+        }
+    }
+}
+
+fn parse_block(lines: &[String]) -> Result<Packet, String> {
+    let command = match lines.first() {
+        Some(c) => c.clone(),
+        None => return Err(String::from("no command")),
+    };
+    if command == "SIGHUP" && lines.len() == 1 {
+        return Ok(Packet::Sighup);
+    }
+    let success = match lines.get(1).map(|s| s.as_str()) {
+        Some("SUCCESS") => true,
+        Some("ERROR") => false,
+        Some(s) => return Err(format!("unexpected status '{}'", s)),
+        None => return Err(format!("no status for '{}'", command)),
+    };
+    let data = match lines.get(2).map(|s| s.as_str()) {
+        None => vec![],
+        Some("DATA") => {
+            let n: usize = lines
+                .get(3)
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| format!("no data length for '{}'", command))?;
+            if lines.len() != n + 4 {
+                return Err(format!(
+                    "expected {} data lines for '{}', got {}",
+                    n,
+                    command,
+                    lines.len().saturating_sub(4)
+                ));
+            }
+            lines[4..].to_vec()
+        }
+        Some(s) => return Err(format!("unexpected line '{}'", s)),
+    };
+    Ok(Packet::Reply(Reply {
+        command,
+        success,
+        data,
+    }))
+}
+
+/// Assembles packets from lircd socket lines
+#[derive(Debug, Default)]
+pub struct PacketReader {
+    block: Option<Vec<String>>,
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        PacketReader::default()
+    }
+
+    /// Drops a block in progress, the lines of a new connection don't
+    /// finish a block of the old one
+    pub fn reset(&mut self) {
+        if self.block.take().is_some() {
+            log::warn!("drop unfinished reply block");
+        }
+    }
+
+    /// Returns a packet when the line completes one
+    pub fn push(&mut self, line: &str) -> Option<Result<Packet, PacketError>> {
+        let line = line.trim_end_matches('\r');
+        match self.block.as_mut() {
+            None => {
+                if line == "BEGIN" {
+                    self.block = Some(vec![]);
+                    return None;
+                }
+                Some(line.parse().map(Packet::Event).map_err(PacketError::Event))
+            }
+            Some(block) => {
+                if line == "END" {
+                    let block = self.block.take().unwrap();
+                    return Some(parse_block(&block).map_err(PacketError::Block));
+                }
+                if block.len() >= MAX_BLOCK_LINES {
+                    self.block = None;
+                    return Some(Err(PacketError::Block(format!(
+                        "more than {} lines",
+                        MAX_BLOCK_LINES
+                    ))));
+                }
+                block.push(line.to_string());
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::EventParseError;
+    use crate::packet::{Packet, PacketError, PacketReader, Reply, MAX_BLOCK_LINES};
+
+    fn read(lines: &[&str]) -> Vec<Result<Packet, PacketError>> {
+        let mut r = PacketReader::new();
+        lines.iter().filter_map(|l| r.push(l)).collect()
+    }
+
+    #[test]
+    fn event() {
+        let res = read(&["0000000000000001 00 KEY_OK d"]);
+        assert_eq!(res.len(), 1);
+        match &res[0] {
+            Ok(Packet::Event(e)) => assert_eq!(e.name, "KEY_OK"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            read(&["a b c"]),
+            vec![Err(PacketError::Event(EventParseError::FieldCount(3)))]
+        );
+    }
+
+    #[test]
+    fn sighup() {
+        assert_eq!(read(&["BEGIN", "SIGHUP", "END"]), vec![Ok(Packet::Sighup)]);
+    }
+
+    #[test]
+    fn reply() {
+        let res = read(&["BEGIN", "VERSION", "SUCCESS", "DATA", "1", "0.10.1", "END"]);
+        let reply = Reply {
+            command: String::from("VERSION"),
+            success: true,
+            data: vec![String::from("0.10.1")],
+        };
+        assert_eq!(res, vec![Ok(Packet::Reply(reply.clone()))]);
+        assert_eq!(
+            reply.to_string(),
+            "BEGIN\nVERSION\nSUCCESS\nDATA\n1\n0.10.1\nEND"
+        );
+        let res = read(&["BEGIN", "SEND_ONCE apple KEY_OK", "ERROR", "END"]);
+        let reply = Reply {
+            command: String::from("SEND_ONCE apple KEY_OK"),
+            success: false,
+            data: vec![],
+        };
+        assert_eq!(
+            Packet::Reply(reply.clone()).to_string(),
+            "BEGIN\nSEND_ONCE apple KEY_OK\nERROR\nEND"
+        );
+        assert_eq!(res, vec![Ok(Packet::Reply(reply))]);
+    }
+
+    #[test]
+    fn reply_between_events() {
+        let res = read(&[
+            "1 0 A d", "BEGIN", "LIST", "SUCCESS", "DATA", "2", "apple", "nec", "END", "1 1 A d",
+        ]);
+        assert_eq!(res.len(), 3);
+        assert!(matches!(res[0], Ok(Packet::Event(_))));
+        match &res[1] {
+            Ok(Packet::Reply(r)) => assert_eq!(r.data, vec!["apple", "nec"]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(res[2], Ok(Packet::Event(_))));
+    }
+
+    #[test]
+    fn reset() {
+        let mut r = PacketReader::new();
+        assert_eq!(r.push("BEGIN"), None);
+        assert_eq!(r.push("VERSION"), None);
+        r.reset();
+        assert!(matches!(r.push("1 0 A d"), Some(Ok(Packet::Event(_)))));
+        r.reset();
+        assert!(matches!(r.push("1 1 A d"), Some(Ok(Packet::Event(_)))));
+    }
+
+    #[test]
+    fn bad_blocks() {
+        assert_eq!(
+            read(&["BEGIN", "END"]),
+            vec![Err(PacketError::Block(String::from("no command")))]
+        );
+        assert_eq!(
+            read(&["BEGIN", "LIST", "OK", "END"]),
+            vec![Err(PacketError::Block(String::from(
+                "unexpected status 'OK'"
+            )))]
+        );
+        assert_eq!(
+            read(&["BEGIN", "LIST", "SUCCESS", "DATA", "2", "a", "END"]),
+            vec![Err(PacketError::Block(String::from(
+                "expected 2 data lines for 'LIST', got 1"
+            )))]
+        );
+        let mut lines = vec!["BEGIN"];
+        lines.extend(std::iter::repeat_n("x", MAX_BLOCK_LINES + 1));
+        lines.push("1 0 A d");
+        let res = read(&lines);
+        assert_eq!(res.len(), 2);
+        assert!(matches!(res[0], Err(PacketError::Block(_))));
+        assert!(matches!(res[1], Ok(Packet::Event(_))));
+    }
+}
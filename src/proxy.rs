@@ -0,0 +1,225 @@
+use crate::packet::Reply;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// lircd commands clients may send through the changer, all lircd takes
+pub const COMMANDS: [&str; 9] = [
+    "SEND_ONCE",
+    "SEND_START",
+    "SEND_STOP",
+    "LIST",
+    "VERSION",
+    "SET_TRANSMITTERS",
+    "SET_INPUTLOG",
+    "DRV_OPTION",
+    "SIMULATE",
+];
+
+#[derive(Default)]
+struct State {
+    writer: Option<Box<dyn Write + Send>>,
+    /// client and command waiting for a lircd reply, in sending order
+    pending: VecDeque<(u32, String)>,
+}
+
+/// Forwards client commands to the upstream lircd connection and finds the
+/// client a lircd reply belongs to
+#[derive(Default)]
+pub struct Upstream {
+    state: Mutex<State>,
+}
+
+fn error_reply(command: &str, msg: &str) -> Reply {
+    Reply {
+        command: command.to_string(),
+        success: false,
+        data: vec![msg.to_string()],
+    }
+}
+
+impl Upstream {
+    pub fn new() -> Self {
+        Upstream::default()
+    }
+
+    /// Sets the writer of a new lircd connection
+    pub fn connect(&self, writer: Box<dyn Write + Send>) {
+        let mut state = self.state.lock().unwrap();
+        state.writer = Some(writer);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().writer.is_some()
+    }
+
+    /// Drops the writer, returns error replies for the commands lircd will
+    /// never answer
+    pub fn disconnect(&self) -> Vec<(u32, Reply)> {
+        let mut state = self.state.lock().unwrap();
+        state.writer = None;
+        state
+            .pending
+            .drain(..)
+            .map(|(id, c)| (id, error_reply(&c, "lircd disconnected")))
+            .collect()
+    }
+
+    /// Writes a client command to lircd. On failure returns an error reply
+    /// for the client.
+    pub fn send(&self, client: u32, line: &str) -> Result<(), Reply> {
+        let command = line.trim();
+        let directive = command.split_whitespace().next().unwrap_or("");
+        if !COMMANDS.contains(&directive.to_uppercase().as_str()) {
+            return Err(error_reply(command, "unsupported command"));
+        }
+        let mut state = self.state.lock().unwrap();
+        let res = match state.writer.as_mut() {
+            Some(w) => w
+                .write_all(format!("{}\n", command).as_bytes())
+                .and_then(|_| w.flush()),
+            None => return Err(error_reply(command, "not connected to lircd")),
+        };
+        if let Err(err) = res {
+            log::error!("Can't write to lircd: {}", err);
+            state.writer = None;
+            return Err(error_reply(command, "can't write to lircd"));
+        }
+        state.pending.push_back((client, command.to_string()));
+        Ok(())
+    }
+
+    /// Returns the client waiting for the reply, `None` if nobody asked
+    pub fn reply(&self, reply: &Reply) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        let i = state
+            .pending
+            .iter()
+            .position(|(_, c)| c.eq_ignore_ascii_case(&reply.command))?;
+        state.pending.remove(i).map(|(id, _)| id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::packet::Reply;
+    use crate::proxy::Upstream;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buf {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buf {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn reply(command: &str) -> Reply {
+        Reply {
+            command: command.to_string(),
+            success: true,
+            data: vec![],
+        }
+    }
+
+    #[test]
+    fn not_connected() {
+        let u = Upstream::new();
+        assert!(!u.is_connected());
+        let err = u.send(1, "VERSION").unwrap_err();
+        assert!(!err.success);
+        assert_eq!(err.command, "VERSION");
+        assert_eq!(err.data, vec!["not connected to lircd"]);
+        assert_eq!(u.reply(&reply("VERSION")), None);
+    }
+
+    #[test]
+    fn unsupported() {
+        let u = Upstream::new();
+        let buf = Buf::default();
+        u.connect(Box::new(buf.clone()));
+        let err = u.send(1, "SEND_TWICE apple KEY_OK").unwrap_err();
+        assert_eq!(err.data, vec!["unsupported command"]);
+        assert!(u.send(1, "").is_err());
+        assert_eq!(buf.text(), "");
+    }
+
+    #[test]
+    fn routes_replies() {
+        let u = Upstream::new();
+        let buf = Buf::default();
+        u.connect(Box::new(buf.clone()));
+        assert_eq!(u.send(1, "VERSION\r"), Ok(()));
+        assert_eq!(u.send(2, " SEND_ONCE apple KEY_OK"), Ok(()));
+        assert_eq!(u.send(3, "list"), Ok(()));
+        assert_eq!(buf.text(), "VERSION\nSEND_ONCE apple KEY_OK\nlist\n");
+        assert_eq!(u.reply(&reply("SEND_ONCE apple KEY_OK")), Some(2));
+        assert_eq!(u.reply(&reply("VERSION")), Some(1));
+        assert_eq!(u.reply(&reply("LIST")), Some(3));
+        assert_eq!(u.reply(&reply("LIST")), None);
+    }
+
+    #[test]
+    fn irsend_commands() {
+        let u = Upstream::new();
+        let buf = Buf::default();
+        u.connect(Box::new(buf.clone()));
+        for (id, c) in [
+            "SEND_START apple KEY_VOLUMEUP",
+            "SEND_STOP apple KEY_VOLUMEUP",
+            "SET_TRANSMITTERS 1 2",
+            "SIMULATE 0000000000000001 00 KEY_OK apple",
+        ]
+        .iter()
+        .enumerate()
+        {
+            assert_eq!(u.send(id as u32, c), Ok(()));
+        }
+        assert_eq!(
+            buf.text(),
+            "SEND_START apple KEY_VOLUMEUP\nSEND_STOP apple KEY_VOLUMEUP\n\
+            SET_TRANSMITTERS 1 2\nSIMULATE 0000000000000001 00 KEY_OK apple\n"
+        );
+        assert_eq!(u.reply(&reply("SET_TRANSMITTERS 1 2")), Some(2));
+        assert_eq!(
+            u.reply(&reply("SIMULATE 0000000000000001 00 KEY_OK apple")),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn same_command_in_order() {
+        let u = Upstream::new();
+        u.connect(Box::new(Buf::default()));
+        u.send(2, "VERSION").unwrap();
+        u.send(1, "VERSION").unwrap();
+        assert_eq!(u.reply(&reply("VERSION")), Some(2));
+        assert_eq!(u.reply(&reply("VERSION")), Some(1));
+    }
+
+    #[test]
+    fn disconnect() {
+        let u = Upstream::new();
+        u.connect(Box::new(Buf::default()));
+        u.send(1, "VERSION").unwrap();
+        assert!(u.is_connected());
+        let res = u.disconnect();
+        assert!(!u.is_connected());
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].0, 1);
+        assert_eq!(res[0].1.data, vec!["lircd disconnected"]);
+        assert_eq!(u.reply(&reply("VERSION")), None);
+        assert!(u.send(1, "VERSION").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::config::SettingsReader;
+use crate::event::Event;
+use crate::keymatch::{self, KeyMatch};
+use crate::mode::Mode;
+use crossbeam_channel::{select, Receiver, Sender};
+
+/// Renames or drops a key, `device` and `name` match the event as it leaves
+/// the HOLD detector, so `KEY_MENU_HOLD` can be mapped apart from `KEY_MENU`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Remap {
+    pub key: KeyMatch,
+    pub to_name: Option<String>,
+    pub to_device: Option<String>,
+    pub drop: bool,
+}
+
+/// Applies the remapping table to events before they are sent to clients
+pub struct Remaps {
+    remaps: Vec<Remap>,
+    mode: Mode,
+}
+
+impl Remaps {
+    pub fn new(remaps: Vec<Remap>, mode: Mode) -> Self {
+        Remaps { remaps, mode }
+    }
+
+    /// Returns the most specific remap for the event in the active mode
+    pub fn find(&self, e: &Event) -> Option<&Remap> {
+        let mode = self.mode.get();
+        keymatch::find(&self.remaps, |r| &r.key, &mode, &e.device, &e.name)
+    }
+
+    /// Returns the rewritten event, `None` if it is dropped. Ends of presses
+    /// pass as they are.
+    pub fn apply(&self, mut e: Event) -> Option<Event> {
+        if e.end {
+            return Some(e);
+        }
+        let r = match self.find(&e) {
+            Some(r) => r,
+            None => return Some(e),
+        };
+        if r.drop {
+            log::debug!("drop {} {}", e.name, e.device);
+            return None;
+        }
+        if let Some(n) = &r.to_name {
+            e.name = n.clone();
+        }
+        if let Some(d) = &r.to_device {
+            e.device = d.clone();
+        }
+        Some(e)
+    }
+
+    /// Reads events from `data` until it or `cl` is closed
+    pub fn run<T: From<Event>>(
+        mut self,
+        data: Receiver<Event>,
+        out: Sender<T>,
+        mut settings: SettingsReader,
+        cl: Receiver<u32>,
+    ) {
+        loop {
+            select! {
+                recv(data) -> msg => {
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Some(s) = settings.changed() {
+                        log::info!("update remaps");
+                        self.remaps = s.remaps.clone();
+                    }
+                    if let Some(e) = self.apply(received) {
+                        out.send(e.into()).unwrap();
+                    }
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in remap");
+                    break;
+                }
+            }
+        }
+        log::info!("exit remap");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::Event;
+    use crate::keymatch::KeyMatch;
+    use crate::mode::Mode;
+    use crate::remap::{Remap, Remaps};
+    use std::str::FromStr;
+
+    fn ev(name: &str, device: &str) -> Event {
+        Event::from_str(&format!("1 0 {} {}", name, device)).unwrap()
+    }
+
+    fn remap(device: Option<&str>, name: Option<&str>, to: Option<&str>) -> Remap {
+        Remap {
+            key: KeyMatch {
+                mode: None,
+                device: device.map(String::from),
+                name: name.map(String::from),
+                ignore_case: false,
+            },
+            to_name: to.map(String::from),
+            to_device: None,
+            drop: false,
+        }
+    }
+
+    #[test]
+    fn no_remaps() {
+        let r = Remaps::new(vec![], Mode::default());
+        assert_eq!(r.apply(ev("KEY_OK", "d")), Some(ev("KEY_OK", "d")));
+    }
+
+    #[test]
+    fn rename() {
+        let r = Remaps::new(
+            vec![remap(None, Some("KEY_MENU"), Some("KEY_CONTEXT_MENU"))],
+            Mode::default(),
+        );
+        assert_eq!(
+            r.apply(ev("KEY_MENU", "apple")),
+            Some(ev("KEY_CONTEXT_MENU", "apple"))
+        );
+        assert_eq!(
+            r.apply(ev("KEY_MENU_HOLD", "apple")),
+            Some(ev("KEY_MENU_HOLD", "apple"))
+        );
+    }
+
+    #[test]
+    fn hold_separately() {
+        let r = Remaps::new(
+            vec![
+                remap(None, Some("KEY_MENU"), Some("KEY_CONTEXT_MENU")),
+                remap(None, Some("KEY_MENU_HOLD"), Some("KEY_HOME")),
+            ],
+            Mode::default(),
+        );
+        assert_eq!(r.apply(ev("KEY_MENU_HOLD", "d")), Some(ev("KEY_HOME", "d")));
+    }
+
+    #[test]
+    fn device() {
+        let mut all = remap(Some("apple"), None, None);
+        all.to_device = Some(String::from("remote"));
+        let r = Remaps::new(
+            vec![
+                all,
+                remap(Some("apple"), Some("KEY_MENU"), Some("KEY_CONTEXT_MENU")),
+            ],
+            Mode::default(),
+        );
+        assert_eq!(r.apply(ev("KEY_OK", "apple")), Some(ev("KEY_OK", "remote")));
+        // the most specific one wins, the device stays
+        assert_eq!(
+            r.apply(ev("KEY_MENU", "apple")),
+            Some(ev("KEY_CONTEXT_MENU", "apple"))
+        );
+        assert_eq!(r.apply(ev("KEY_MENU", "mce")), Some(ev("KEY_MENU", "mce")));
+    }
+
+    #[test]
+    fn drop() {
+        let mut d = remap(Some("mce"), Some("KEY_POWER"), None);
+        d.drop = true;
+        let r = Remaps::new(vec![d], Mode::default());
+        assert_eq!(r.apply(ev("KEY_POWER", "mce")), None);
+        // the end of the press goes to the event server as it is
+        let end = ev("KEY_POWER", "mce").to_end();
+        assert_eq!(r.apply(end.clone()), Some(end));
+        assert_eq!(
+            r.apply(ev("KEY_POWER", "apple")),
+            Some(ev("KEY_POWER", "apple"))
+        );
+    }
+
+    #[test]
+    fn mode() {
+        let mut tv = remap(None, Some("KEY_UP"), Some("KEY_CHANNELUP"));
+        tv.key.mode = Some(String::from("tv"));
+        let m = Mode::default();
+        let r = Remaps::new(vec![tv, remap(None, Some("KEY_UP"), Some("Up"))], m.clone());
+        assert_eq!(r.apply(ev("KEY_UP", "d")), Some(ev("Up", "d")));
+        m.set("tv");
+        assert_eq!(r.apply(ev("KEY_UP", "d")), Some(ev("KEY_CHANNELUP", "d")));
+    }
+
+    #[test]
+    fn keeps_code_and_repeat() {
+        let r = Remaps::new(
+            vec![remap(None, Some("KEY_UP"), Some("Up"))],
+            Mode::default(),
+        );
+        let e = Event::from_str("800f7422 3 KEY_UP d").unwrap();
+        assert_eq!(r.apply(e).unwrap().to_string(), "00000000800f7422 03 Up d");
+    }
+}
@@ -1,13 +1,9 @@
 use clap::{App, Arg};
-use std::collections::HashMap;
+use crossbeam_channel::unbounded;
+use lirc_changer_rust::server;
 use std::io::prelude::*;
 use std::os::unix::net::UnixListener;
-use std::os::unix::net::UnixStream;
-use std::path::Path;
 use std::sync::mpsc;
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
-use std::sync::Mutex;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time;
@@ -30,77 +26,51 @@ fn main() {
     log::info!("Starting sender");
 
     let out_path = matches.value_of("socketOut").unwrap_or("test");
-    if Path::new(out_path).exists() {
-        std::fs::remove_file(out_path).unwrap();
-    }
     log::info!("Opening incomming socket {}", out_path);
-    let listener = match UnixListener::bind(out_path) {
+    let listener = match server::bind(out_path) {
         Err(_) => panic!("failed to bind socket"),
         Ok(stream) => stream,
     };
 
     let (tj, rx) = spawn_stdin_channel();
-    let (t1, r1) = mpsc::channel();
+    let (t1, r1) = unbounded();
     thread::spawn(move || map(rx, t1));
 
-    let receivers: HashMap<u32, Sender<String>> = HashMap::new();
-    let l_receivers = Arc::new(Mutex::new(receivers));
-    let rc = l_receivers.clone();
-    thread::spawn(move || broadcast(r1, rc));
-
-    thread::spawn(move || {
-        let mut num = 0;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    num += 1;
-                    let cr = l_receivers.clone();
-                    thread::spawn(move || handle_client(stream, cr, num));
-                }
-                Err(err) => {
-                    eprintln!("Error: {}", err);
-                    break;
-                }
-            }
-        }
-    });
+    let (rtx, rrx) = unbounded();
+    let (t_close, r_close) = unbounded::<u32>();
+    let rtxc = rtx.clone();
+    thread::spawn(move || server::broadcast(r1, rrx, rtxc, r_close));
+    thread::spawn(move || accept(listener, rtx));
 
     log::info!("Waiting for stdin close");
     tj.join().unwrap();
+    drop(t_close);
 
     log::info!("Removing pipe");
     std::fs::remove_file(out_path).unwrap();
     log::info!("Bye!");
 }
 
-fn handle_client(
-    mut stream: UnixStream,
-    receivers: Arc<Mutex<HashMap<u32, Sender<String>>>>,
-    num: u32,
-) {
-    let (txl, rxl) = mpsc::channel::<String>();
-    {
-        let mut lr = receivers.lock().unwrap();
-        lr.insert(num, txl);
-        log::info!("connected {}. len = {}", num, lr.len());
-    }
-    stream
-        .write_all(format!("Hi {}\n", num).as_bytes())
-        .unwrap();
-    for received in rxl {
-        match stream.write_all(received.as_bytes()) {
-            Ok(_) => {
-                log::info!("Wrote msg to {}", num);
+/// Same as `server::accept`, but greets every client with its number first
+fn accept(listener: UnixListener, info: crossbeam_channel::Sender<server::Msg>) {
+    let ids = server::Ids::default();
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let num = ids.next();
+                if let Err(err) = stream.write_all(format!("Hi {}\n", num).as_bytes()) {
+                    log::warn!("Can't greet {}. {}", num, err);
+                    continue;
+                }
+                let infoc = info.clone();
+                thread::spawn(move || server::handle_client(stream, infoc, None, num));
             }
             Err(err) => {
-                log::error!("err: {}", err);
+                log::error!("Error: {}", err);
                 break;
             }
         }
     }
-    let mut lr = receivers.lock().unwrap();
-    lr.remove(&num);
-    log::info!("disconnected {}. len = {}", num, lr.len());
 }
 
 enum Input {
@@ -150,37 +120,19 @@ fn spawn_stdin_channel() -> (JoinHandle<()>, mpsc::Receiver<String>) {
     (res, res_rx)
 }
 
-fn broadcast(data: mpsc::Receiver<String>, receivers: Arc<Mutex<HashMap<u32, Sender<String>>>>) {
-    for received in data {
-        log::info! {"Got from stdin {}", received}
-        let tmp = received + "\n";
-        let lr = receivers.lock().unwrap();
-        for (key, value) in lr.iter() {
-            let s = tmp.clone();
-            match value.send(s) {
-                Ok(_) => {
-                    log::info!("send {} to {}", tmp, key);
-                }
-                Err(err) => {
-                    log::error!("Can't send to {}: {}", key, err);
-                }
-            }
-        }
-    }
-    log::info!("Stopped broadcast");
-}
-
-fn map(data: mpsc::Receiver<String>, out: Sender<String>) {
+fn map(data: mpsc::Receiver<String>, out: crossbeam_channel::Sender<String>) {
     for received in data {
         log::info! {"Got from stdin {}", received}
         if received == "s" {
-            out.send(String::from("qwe 0 KEY_UP device")).unwrap()
+            out.send(String::from("0000000000000001 00 KEY_UP device"))
+                .unwrap()
         } else if received == "a" {
             for i in 0..10 {
                 if i > 0 {
                     thread::sleep(time::Duration::from_millis(80));
                 }
-                out.send(format!("qwe {} KEY_UP device", i)).unwrap();
+                out.send(format!("0000000000000001 {:02x} KEY_UP device", i))
+                    .unwrap();
             }
         } else {
             out.send(received).unwrap()
@@ -0,0 +1,330 @@
+use crate::config::SettingsReader;
+use crate::event::Event;
+use crossbeam_channel::{after, never, select, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Sequence {
+    pub keys: Vec<String>,
+    pub device: Option<String>,
+    pub within: Duration,
+    pub emit: String,
+    /// keys and device match in any case, as lircrc buttons and remotes do
+    pub ignore_case: bool,
+}
+
+impl Sequence {
+    fn accepts(&self, e: &Event) -> bool {
+        self.device.as_ref().is_none_or(|d| self.same(&e.device, d))
+    }
+
+    fn starts_with(&self, events: &[(Event, Instant)]) -> bool {
+        events.len() <= self.keys.len()
+            && events
+                .iter()
+                .zip(self.keys.iter())
+                .all(|((e, _), k)| self.same(&e.name, k) && self.accepts(e))
+    }
+
+    fn same(&self, got: &str, want: &str) -> bool {
+        match self.ignore_case {
+            true => got.eq_ignore_ascii_case(want),
+            false => got == want,
+        }
+    }
+
+    fn is(&self, events: &[(Event, Instant)]) -> bool {
+        events.len() == self.keys.len() && self.starts_with(events)
+    }
+}
+
+/// Matches configured key sequences, e.g. MENU then PLAY, and replaces them
+/// with one event. Presses that may start a sequence are held back until the
+/// sequence completes, breaks or times out. Ends of held back presses wait
+/// for them.
+pub struct Sequences {
+    sequences: Vec<Sequence>,
+    buffer: Vec<(Event, Instant)>,
+    ends: Vec<Event>,
+}
+
+impl Sequences {
+    pub fn new(sequences: Vec<Sequence>) -> Self {
+        Sequences {
+            sequences,
+            buffer: vec![],
+            ends: vec![],
+        }
+    }
+
+    /// Returns the time when buffered presses must be released.
+    pub fn deadline(&self) -> Option<Instant> {
+        let (_, at) = self.buffer.first()?;
+        self.sequences
+            .iter()
+            .filter(|s| s.starts_with(&self.buffer))
+            .map(|s| *at + s.within)
+            .max()
+    }
+
+    pub fn push(&mut self, e: Event, now: Instant) -> Vec<Event> {
+        if e.end && !self.buffer.is_empty() {
+            self.ends.push(e);
+            return vec![];
+        }
+        if e.end {
+            return vec![e];
+        }
+        let mut res = self.add(e, now);
+        if self.buffer.is_empty() {
+            res.append(&mut self.ends);
+        }
+        res
+    }
+
+    fn add(&mut self, e: Event, now: Instant) -> Vec<Event> {
+        let mut res = vec![];
+        if e.repeat > 0 {
+            res.append(&mut self.take());
+            res.push(e);
+            return res;
+        }
+        if self.deadline().is_some_and(|d| now > d) {
+            res.append(&mut self.take());
+        }
+        self.buffer.push((e, now));
+        loop {
+            if let Some(s) = self.sequences.iter().find(|s| s.is(&self.buffer)) {
+                log::debug!("sequence {}", s.emit);
+                let (last, _) = self.buffer.last().unwrap();
+                res.push(Event {
+                    code: last.code,
+                    repeat: 0,
+                    name: s.emit.clone(),
+                    device: last.device.clone(),
+                    source: last.source,
+                    end: false,
+                });
+                self.buffer.clear();
+                self.ends.clear();
+                return res;
+            }
+            if self.sequences.iter().any(|s| s.starts_with(&self.buffer)) {
+                return res;
+            }
+            res.push(self.buffer.remove(0).0);
+            if self.buffer.is_empty() {
+                return res;
+            }
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) -> Vec<Event> {
+        match self.deadline() {
+            Some(d) if now >= d => self.take(),
+            _ => vec![],
+        }
+    }
+
+    /// Replaces sequences, releases buffered presses that can't match any more
+    pub fn update(&mut self, sequences: Vec<Sequence>) -> Vec<Event> {
+        self.sequences = sequences;
+        if self.sequences.iter().any(|s| s.starts_with(&self.buffer)) {
+            return vec![];
+        }
+        self.take()
+    }
+
+    /// Reads events from `data` until it or `cl` is closed
+    pub fn run<T: From<Event>>(
+        mut self,
+        data: Receiver<Event>,
+        out: Sender<T>,
+        mut settings: SettingsReader,
+        cl: Receiver<u32>,
+    ) {
+        loop {
+            let update = match self.deadline() {
+                Some(d) => after(d.saturating_duration_since(Instant::now())),
+                None => never(),
+            };
+            select! {
+                recv(update) -> _ => {
+                    for e in self.expire(Instant::now()) {
+                        out.send(e.into()).unwrap();
+                    }
+                }
+                recv(data) -> msg => {
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Some(s) = settings.changed() {
+                        log::info!("update sequences");
+                        for e in self.update(s.sequences.clone()) {
+                            out.send(e.into()).unwrap();
+                        }
+                    }
+                    for e in self.push(received, Instant::now()) {
+                        out.send(e.into()).unwrap();
+                    }
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in combine");
+                    break;
+                }
+            }
+        }
+        log::info!("exit combine");
+    }
+
+    fn take(&mut self) -> Vec<Event> {
+        let mut res = self.buffer.drain(..).map(|(e, _)| e).collect::<Vec<_>>();
+        res.append(&mut self.ends);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::Event;
+    use crate::sequence::{Sequence, Sequences};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    fn ev(name: &str) -> Event {
+        Event::from_str(&format!("1 0 {} d", name)).unwrap()
+    }
+
+    fn names(events: Vec<Event>) -> Vec<String> {
+        events.into_iter().map(|e| e.name).collect()
+    }
+
+    fn seqs() -> Sequences {
+        Sequences::new(vec![
+            Sequence {
+                keys: vec![String::from("MENU"), String::from("PLAY")],
+                device: None,
+                within: Duration::from_millis(300),
+                emit: String::from("MENU+PLAY"),
+                ignore_case: false,
+            },
+            Sequence {
+                keys: vec![String::from("A"), String::from("B"), String::from("C")],
+                device: Some(String::from("d")),
+                within: Duration::from_millis(500),
+                emit: String::from("ABC"),
+                ignore_case: true,
+            },
+        ])
+    }
+
+    #[test]
+    fn match_sequence() {
+        let mut s = seqs();
+        let now = Instant::now();
+        assert!(s.push(ev("MENU"), now).is_empty());
+        assert_eq!(s.deadline(), Some(now + Duration::from_millis(300)));
+        assert_eq!(
+            names(s.push(ev("PLAY"), now + Duration::from_millis(100))),
+            vec!["MENU+PLAY"]
+        );
+        assert_eq!(s.deadline(), None);
+    }
+
+    #[test]
+    fn ends() {
+        let mut s = seqs();
+        let now = Instant::now();
+        let end = |name| ev(name).to_end();
+        assert_eq!(s.push(end("UP"), now), vec![end("UP")]);
+        // the end of a held back press follows it
+        assert!(s.push(ev("MENU"), now).is_empty());
+        assert!(s.push(end("MENU"), now).is_empty());
+        assert_eq!(
+            s.push(ev("UP"), now),
+            vec![ev("MENU"), ev("UP"), end("MENU")]
+        );
+        assert!(s.push(ev("MENU"), now).is_empty());
+        assert!(s.push(end("MENU"), now).is_empty());
+        assert_eq!(
+            s.expire(now + Duration::from_millis(300)),
+            vec![ev("MENU"), end("MENU")]
+        );
+        // and is dropped with it
+        assert!(s.push(ev("MENU"), now).is_empty());
+        assert!(s.push(end("MENU"), now).is_empty());
+        assert_eq!(names(s.push(ev("PLAY"), now)), vec!["MENU+PLAY"]);
+        assert_eq!(s.push(end("PLAY"), now), vec![end("PLAY")]);
+    }
+
+    #[test]
+    fn pass_other() {
+        let mut s = seqs();
+        let now = Instant::now();
+        assert_eq!(names(s.push(ev("PLAY"), now)), vec!["PLAY"]);
+        assert!(s.push(ev("MENU"), now).is_empty());
+        assert_eq!(names(s.push(ev("UP"), now)), vec!["MENU", "UP"]);
+    }
+
+    #[test]
+    fn restart_on_break() {
+        let mut s = seqs();
+        let now = Instant::now();
+        assert!(s.push(ev("A"), now).is_empty());
+        assert!(s.push(ev("B"), now).is_empty());
+        assert_eq!(names(s.push(ev("A"), now)), vec!["A", "B"]);
+        assert!(s.push(ev("B"), now).is_empty());
+        // keys of this one match in any case
+        assert_eq!(names(s.push(ev("c"), now)), vec!["ABC"]);
+    }
+
+    #[test]
+    fn expire() {
+        let mut s = seqs();
+        let now = Instant::now();
+        assert!(s.push(ev("MENU"), now).is_empty());
+        assert!(s.expire(now + Duration::from_millis(200)).is_empty());
+        assert_eq!(
+            names(s.expire(now + Duration::from_millis(300))),
+            vec!["MENU"]
+        );
+        assert!(s.push(ev("MENU"), now).is_empty());
+        assert_eq!(
+            names(s.push(ev("PLAY"), now + Duration::from_millis(400))),
+            vec!["MENU", "PLAY"]
+        );
+    }
+
+    #[test]
+    fn repeats_and_device() {
+        let mut s = seqs();
+        let now = Instant::now();
+        assert!(s.push(ev("MENU"), now).is_empty());
+        let mut r = ev("MENU");
+        r.repeat = 1;
+        assert_eq!(names(s.push(r, now)), vec!["MENU", "MENU"]);
+        let mut a = ev("A");
+        a.device = String::from("other");
+        assert_eq!(names(s.push(a, now)), vec!["A"]);
+        // the device of a sequence matching in any case does too
+        let mut a = ev("A");
+        a.device = String::from("D");
+        assert!(s.push(a, now).is_empty());
+    }
+
+    #[test]
+    fn update() {
+        let mut s = seqs();
+        let now = Instant::now();
+        assert!(s.push(ev("MENU"), now).is_empty());
+        let mut new = seqs().sequences;
+        assert!(s.update(new.clone()).is_empty());
+        new.remove(0);
+        assert_eq!(names(s.update(new)), vec!["MENU"]);
+        assert_eq!(s.deadline(), None);
+    }
+}
@@ -0,0 +1,342 @@
+use crate::cidr::Cidr;
+use crossbeam_channel::{select, Receiver, Sender};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+pub enum Msg {
+    Init(u32, mpsc::Sender<String>),
+    Close(u32),
+    /// sends a line to one client only
+    Send(u32, String),
+    /// asks for the connected clients
+    List(Sender<Listing>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientInfo {
+    pub id: u32,
+    pub connected: Instant,
+    /// lines sent to the client
+    pub sent: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Listing {
+    /// lines broadcast since the start
+    pub sent: u64,
+    pub clients: Vec<ClientInfo>,
+}
+
+struct Client {
+    tx: mpsc::Sender<String>,
+    connected: Instant,
+    sent: u64,
+}
+
+/// Client numbers, unique over all the listeners of one `broadcast`
+#[derive(Debug, Default)]
+pub struct Ids(AtomicU32);
+
+impl Ids {
+    pub fn next(&self) -> u32 {
+        self.0.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+/// TCP output listener
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TcpOutput {
+    pub address: SocketAddr,
+    /// clients allowed to connect, no one if empty
+    pub allow: Vec<Cidr>,
+}
+
+/// A client connection the line protocol runs on
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> std::io::Result<Self>;
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> std::io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+}
+
+/// Binds a unix socket, removing a stale socket file first
+pub fn bind(path: &str) -> std::io::Result<UnixListener> {
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path)
+}
+
+/// Accepts clients and registers them in `broadcast` via `info`. Lines the
+/// clients write go to `commands` with the client number, or are dropped if
+/// it is `None`.
+pub fn accept(
+    listener: UnixListener,
+    info: Sender<Msg>,
+    commands: Option<Sender<(u32, String)>>,
+    ids: Arc<Ids>,
+) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let infoc = info.clone();
+                let commandsc = commands.clone();
+                let num = ids.next();
+                thread::spawn(move || handle_client(stream, infoc, commandsc, num));
+            }
+            Err(err) => {
+                log::error!("Error: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Same as `accept` for a TCP listener, clients not in `allow` are dropped,
+/// all of them if it is empty
+pub fn accept_tcp(
+    listener: TcpListener,
+    allow: Vec<Cidr>,
+    info: Sender<Msg>,
+    commands: Option<Sender<(u32, String)>>,
+    ids: Arc<Ids>,
+) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::error!("Error: {}", err);
+                break;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(p) => p,
+            Err(err) => {
+                log::warn!("Can't get the client address: {}", err);
+                continue;
+            }
+        };
+        if !allow.iter().any(|c| c.contains(&peer.ip())) {
+            log::warn!("Client {} is not allowed", peer);
+            continue;
+        }
+        let infoc = info.clone();
+        let commandsc = commands.clone();
+        let num = ids.next();
+        log::info!("Client {} from {}", num, peer);
+        thread::spawn(move || handle_client(stream, infoc, commandsc, num));
+    }
+}
+
+pub fn handle_client<S: Stream>(
+    mut stream: S,
+    info: Sender<Msg>,
+    commands: Option<Sender<(u32, String)>>,
+    num: u32,
+) {
+    log::info!("connected {}", num);
+    match stream.try_clone() {
+        Ok(s) => {
+            thread::spawn(move || read_commands(s, commands, num));
+        }
+        Err(err) => log::warn!("Can't read from {}. {}", num, err),
+    }
+    let (tx, rx): (mpsc::Sender<String>, mpsc::Receiver<String>) = mpsc::channel();
+    info.send(Msg::Init(num, tx)).unwrap();
+    for received in rx {
+        log::debug!("Got: {}", &received);
+        match stream.write_all((received.clone() + "\n").as_bytes()) {
+            Ok(_) => {
+                log::debug!("Wrote: {}", &received);
+            }
+            Err(err) => {
+                log::warn!("Can't write to {}. {}", num, err);
+                break;
+            }
+        }
+    }
+    log::info!("disconnected {}", num);
+
+    match info.send(Msg::Close(num)) {
+        Ok(_) => {}
+        Err(err) => {
+            log::warn!("{}", err);
+        }
+    }
+}
+
+fn read_commands<S: Stream>(stream: S, commands: Option<Sender<(u32, String)>>, num: u32) {
+    for line in BufReader::new(stream).lines() {
+        let l = match line {
+            Ok(l) => l,
+            Err(err) => {
+                log::debug!("Can't read from {}. {}", num, err);
+                break;
+            }
+        };
+        log::debug!("Command from {}: {}", num, &l);
+        match &commands {
+            Some(c) => {
+                if c.send((num, l)).is_err() {
+                    break;
+                }
+            }
+            None => log::warn!("Ignoring command from {}: {}", num, l),
+        }
+    }
+}
+
+/// Sends every item of `data` to all connected clients until `cl` is closed
+pub fn broadcast<T: Display + Send + 'static>(
+    data: Receiver<T>,
+    info: Receiver<Msg>,
+    close_info: Sender<Msg>,
+    cl: Receiver<u32>,
+) {
+    let receivers: HashMap<u32, Client> = HashMap::new();
+    let l_receivers = Arc::new(Mutex::new(receivers));
+    let rc = l_receivers.clone();
+    let sent = Arc::new(AtomicU64::new(0));
+    let sc = sent.clone();
+    thread::spawn(move || {
+        for received in data {
+            let s = received.to_string();
+            log::debug!("Got {}", &s);
+            sc.fetch_add(1, Ordering::Relaxed);
+            let mut lr = rc.lock().unwrap();
+            for (key, value) in lr.iter_mut() {
+                let s = s.clone();
+                match value.tx.send(s) {
+                    Ok(_) => {
+                        value.sent += 1;
+                        log::debug!("send {}", key);
+                    }
+                    Err(err) => {
+                        log::error!("Can't send to {}. {}", key, err);
+                        close_info.send(Msg::Close(*key)).unwrap();
+                    }
+                }
+            }
+        }
+    });
+
+    loop {
+        select! {
+            recv(info) -> msg => {
+                let received = msg.unwrap();
+                match received {
+                    Msg::Init(id, stream) => {
+                        log::info!("Got init: {}", id);
+                        let mut lr = l_receivers.lock().unwrap();
+                        lr.insert(id, Client { tx: stream, connected: Instant::now(), sent: 0 });
+                        log::info!("Clients: {}", lr.len());
+                    }
+                    Msg::Close(id) => {
+                        log::info!("Got close: {}", id);
+                        let mut lr = l_receivers.lock().unwrap();
+                        lr.remove(&id);
+                        log::info!("Clients: {}", lr.len());
+                    }
+                    Msg::Send(id, s) => {
+                        let lr = l_receivers.lock().unwrap();
+                        match lr.get(&id) {
+                            Some(c) => {
+                                if let Err(err) = c.tx.send(s) {
+                                    log::error!("Can't send to {}. {}", id, err);
+                                }
+                            }
+                            None => log::warn!("No client {} for the reply", id),
+                        }
+                    }
+                    Msg::List(res) => {
+                        let lr = l_receivers.lock().unwrap();
+                        let mut clients = lr
+                            .iter()
+                            .map(|(id, c)| ClientInfo { id: *id, connected: c.connected, sent: c.sent })
+                            .collect::<Vec<_>>();
+                        clients.sort_by_key(|c| c.id);
+                        let listing = Listing { sent: sent.load(Ordering::Relaxed), clients };
+                        if let Err(err) = res.send(listing) {
+                            log::warn!("{}", err);
+                        }
+                    }
+                }
+            }
+            recv(cl) -> _ => {
+                log::debug!("event from close channel in broadcast");
+                break;
+            }
+        }
+    }
+    log::info!("exit broadcast");
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cidr::Cidr;
+    use crate::server::{accept_tcp, Ids, Msg};
+    use crossbeam_channel::unbounded;
+    use std::io::prelude::*;
+    use std::io::BufReader;
+    use std::net::{TcpListener, TcpStream};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn listen(allow: &[&str]) -> (TcpStream, crossbeam_channel::Receiver<Msg>) {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = l.local_addr().unwrap();
+        let (info, r_info) = unbounded();
+        let allow = allow.iter().map(|c| Cidr::from_str(c).unwrap()).collect();
+        let ids = Arc::new(Ids::default());
+        thread::spawn(move || accept_tcp(l, allow, info, None, ids));
+        (TcpStream::connect(addr).unwrap(), r_info)
+    }
+
+    #[test]
+    fn tcp_client() {
+        let (stream, info) = listen(&["127.0.0.0/8"]);
+        let tx = match info.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Msg::Init(1, tx) => tx,
+            _ => panic!("expected init of client 1"),
+        };
+        tx.send(String::from("0000000000000001 00 KEY_OK d"))
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "0000000000000001 00 KEY_OK d\n");
+    }
+
+    #[test]
+    fn tcp_not_allowed() {
+        for allow in [&["10.0.0.0/8"][..], &[]] {
+            let (mut stream, info) = listen(allow);
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut buf = [0; 8];
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+            assert!(info.try_recv().is_err());
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Counters shown by the control socket `STATS` command
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// events read from lircd
+    pub events: AtomicU64,
+    pub parse_errors: AtomicU64,
+    /// connections to lircd
+    pub connects: AtomicU64,
+    /// client commands forwarded to lircd
+    pub commands: AtomicU64,
+    /// events added with `INJECT`
+    pub injected: AtomicU64,
+}
+
+impl Stats {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns `<name> <value>` lines
+    pub fn lines(&self) -> Vec<String> {
+        [
+            ("events_in", &self.events),
+            ("parse_errors", &self.parse_errors),
+            ("connects", &self.connects),
+            ("commands", &self.commands),
+            ("injected", &self.injected),
+        ]
+        .iter()
+        .map(|(n, c)| format!("{} {}", n, c.load(Ordering::Relaxed)))
+        .collect()
+    }
+}
+
+/// Connection state of every input, shown by the control socket `STATUS`
+#[derive(Debug, Default)]
+pub struct Inputs(Vec<(String, AtomicBool)>);
+
+impl Inputs {
+    /// Inputs named as on the command line, none connected yet
+    pub fn new<I: IntoIterator<Item = String>>(names: I) -> Self {
+        Inputs(
+            names
+                .into_iter()
+                .map(|n| (n, AtomicBool::new(false)))
+                .collect(),
+        )
+    }
+
+    pub fn set(&self, index: usize, connected: bool) {
+        if let Some((_, c)) = self.0.get(index) {
+            c.store(connected, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns `input <index> <name> connected|disconnected` lines
+    pub fn lines(&self) -> Vec<String> {
+        self.0
+            .iter()
+            .enumerate()
+            .map(|(i, (n, c))| {
+                let state = match c.load(Ordering::Relaxed) {
+                    true => "connected",
+                    false => "disconnected",
+                };
+                format!("input {} {} {}", i, n, state)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::{Inputs, Stats};
+
+    #[test]
+    fn lines() {
+        let s = Stats::default();
+        Stats::inc(&s.events);
+        Stats::inc(&s.events);
+        Stats::inc(&s.injected);
+        assert_eq!(
+            s.lines(),
+            vec![
+                "events_in 2",
+                "parse_errors 0",
+                "connects 0",
+                "commands 0",
+                "injected 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn inputs() {
+        let i = Inputs::new([String::from("/run/lircd"), String::from("tcp://tv:8765")]);
+        i.set(1, true);
+        i.set(2, true);
+        assert_eq!(
+            i.lines(),
+            vec![
+                "input 0 /run/lircd disconnected",
+                "input 1 tcp://tv:8765 connected"
+            ]
+        );
+        i.set(1, false);
+        assert_eq!(i.lines()[1], "input 1 tcp://tv:8765 disconnected");
+    }
+}
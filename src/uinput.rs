@@ -0,0 +1,319 @@
+use crate::config::SettingsReader;
+use crate::evdev::{ioctl, InputEvent, EV_KEY, EV_SYN, KEY_PRESS, KEY_RELEASE, SYN_REPORT};
+use crate::event::Event;
+use crate::keycodes;
+use crossbeam_channel::{select, Receiver, Sender};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+
+pub const DEFAULT_PATH: &str = "/dev/uinput";
+pub const DEFAULT_NAME: &str = "lirc-changer";
+
+/// `ioctl` requests of `linux/uinput.h`
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;
+const UI_SET_EVBIT: libc::c_ulong = 0x40045564;
+const UI_SET_KEYBIT: libc::c_ulong = 0x40045565;
+const BUS_VIRTUAL: u16 = 0x06;
+const UINPUT_MAX_NAME_SIZE: usize = 80;
+/// `absmax`, `absmin`, `absfuzz` and `absflat` arrays of `uinput_user_dev`
+const ABS_SIZE: usize = 4 * 64 * 4;
+
+/// Virtual keyboard to create, at start only
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct UinputOutput {
+    pub path: String,
+    /// device name shown to applications
+    pub name: String,
+}
+
+/// Event names to key codes. Names of keys like `KEY_OK` map to themselves,
+/// others only if they are in the map. `_HOLD`, tap and stage variants not
+/// in the map are typed as their key with the modifier, if there is one.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct KeyMap {
+    keys: HashMap<String, u16>,
+    modifier: Option<u16>,
+}
+
+impl KeyMap {
+    /// Makes the map from event names and key names, like
+    /// `KEY_OK_HOLD = KEY_CONTEXT_MENU`
+    pub fn new<'a, I>(keys: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = (&'a str, &'a str)>,
+    {
+        let mut res = HashMap::new();
+        for (name, key) in keys {
+            let code = key_code(key).ok_or_else(|| format!("'{}' is not a key name", key))?;
+            res.insert(name.to_string(), code);
+        }
+        Ok(KeyMap {
+            keys: res,
+            modifier: None,
+        })
+    }
+
+    /// Sets the key held while typing unmapped variants of keys
+    pub fn with_modifier(mut self, key: &str) -> Result<Self, String> {
+        let code = key_code(key).ok_or_else(|| format!("'{}' is not a key name", key))?;
+        self.modifier = Some(code);
+        Ok(self)
+    }
+
+    pub fn code(&self, name: &str) -> Option<u16> {
+        self.keys.get(name).copied().or_else(|| key_code(name))
+    }
+
+    /// Codes of the keys typed for the event, the modifier first. Empty if
+    /// the event has no key.
+    pub fn codes(&self, name: &str) -> Vec<u16> {
+        if let Some(c) = self.code(name) {
+            return vec![c];
+        }
+        match self.modifier.zip(base_code(name)) {
+            Some((m, c)) => vec![m, c],
+            None => vec![],
+        }
+    }
+}
+
+/// Code of the longest key name followed by `_` that `name` starts with,
+/// like `KEY_UP` of `KEY_UP_HOLD2`
+fn base_code(name: &str) -> Option<u16> {
+    name.rmatch_indices('_')
+        .find_map(|(i, _)| key_code(&name[..i]))
+}
+
+/// Code of a `KEY_` name, buttons are left out not to make a mouse
+fn key_code(name: &str) -> Option<u16> {
+    name.starts_with("KEY_")
+        .then(|| keycodes::code(name))
+        .flatten()
+}
+
+/// Where the key events go: the uinput device or a recorder in tests
+pub trait EventWriter {
+    fn write_events(&mut self, events: &[InputEvent]) -> std::io::Result<()>;
+}
+
+/// Virtual keyboard of `/dev/uinput`, destroyed on drop
+pub struct Device {
+    file: File,
+}
+
+/// `struct uinput_user_dev` of the legacy setup, no absolute axes
+fn user_dev(name: &str) -> Vec<u8> {
+    let mut res = vec![0; UINPUT_MAX_NAME_SIZE];
+    let n = name.len().min(UINPUT_MAX_NAME_SIZE - 1);
+    res[..n].copy_from_slice(&name.as_bytes()[..n]);
+    // bustype, vendor, product, version
+    for v in [BUS_VIRTUAL, 0, 0, 1] {
+        res.extend(v.to_ne_bytes());
+    }
+    // ff_effects_max
+    res.extend(0u32.to_ne_bytes());
+    res.extend([0; ABS_SIZE]);
+    res
+}
+
+impl Device {
+    /// Creates a device that may send every `KEY_` code
+    pub fn create(out: &UinputOutput) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new().write(true).open(&out.path)?;
+        ioctl(&file, UI_SET_EVBIT, libc::c_int::from(EV_KEY))?;
+        for (code, _) in keycodes::all().filter(|(_, n)| n.starts_with("KEY_")) {
+            ioctl(&file, UI_SET_KEYBIT, libc::c_int::from(code))?;
+        }
+        file.write_all(&user_dev(&out.name))?;
+        ioctl(&file, UI_DEV_CREATE, 0)?;
+        Ok(Device { file })
+    }
+}
+
+impl EventWriter for Device {
+    fn write_events(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+        let data = events.iter().flat_map(|e| e.to_bytes()).collect::<Vec<_>>();
+        self.file.write_all(&data)
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        if let Err(err) = ioctl(&self.file, UI_DEV_DESTROY, 0) {
+            log::warn!("Can't destroy uinput device: {}", err);
+        }
+    }
+}
+
+/// Types the events as key presses on a virtual keyboard
+pub struct Keyboard<W: EventWriter> {
+    writer: W,
+    keys: KeyMap,
+}
+
+impl<W: EventWriter> Keyboard<W> {
+    pub fn new(writer: W, keys: KeyMap) -> Self {
+        Keyboard { writer, keys }
+    }
+
+    /// Writes presses of the event's keys and their releases in reverse,
+    /// returns false if the event has no key
+    pub fn emit(&mut self, e: &Event) -> std::io::Result<bool> {
+        let codes = self.keys.codes(&e.name);
+        if codes.is_empty() || e.end {
+            return Ok(false);
+        }
+        let syn = InputEvent::new(EV_SYN, SYN_REPORT, 0);
+        let presses = codes.iter().map(|&c| (c, KEY_PRESS));
+        let releases = codes.iter().rev().map(|&c| (c, KEY_RELEASE));
+        let events = presses
+            .chain(releases)
+            .flat_map(|(c, v)| [InputEvent::new(EV_KEY, c, v), syn])
+            .collect::<Vec<_>>();
+        self.writer.write_events(&events)?;
+        Ok(true)
+    }
+
+    /// Passes events from `data` to `out` typing them, until `data` or `cl`
+    /// is closed
+    pub fn run(
+        mut self,
+        data: Receiver<Event>,
+        out: Sender<Event>,
+        mut settings: SettingsReader,
+        cl: Receiver<u32>,
+    ) {
+        loop {
+            select! {
+                recv(data) -> msg => {
+                    let received = match msg {
+                        Ok(msg) => msg,
+                        Err(err) => {
+                            log::warn!("{}", err);
+                            break;
+                        }
+                    };
+                    if let Some(s) = settings.changed() {
+                        log::info!("update uinput keys");
+                        self.keys = s.keymap.clone();
+                    }
+                    match self.emit(&received) {
+                        _ if received.end => {}
+                        Ok(true) => {}
+                        Ok(false) => log::debug!("no key for {}, not typed", received.name),
+                        Err(err) => log::error!("Can't write to uinput: {}", err),
+                    }
+                    out.send(received).unwrap();
+                }
+                recv(cl) -> _ => {
+                    log::debug!("event from close channel in uinput");
+                    break;
+                }
+            }
+        }
+        log::info!("exit uinput");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evdev::{InputEvent, EV_KEY, EV_SYN, KEY_PRESS, KEY_RELEASE, SYN_REPORT};
+    use crate::event::Event;
+    use crate::uinput::{user_dev, EventWriter, KeyMap, Keyboard};
+    use std::str::FromStr;
+
+    const KEY_OK: u16 = 0x160;
+    const KEY_CONTEXT_MENU: u16 = 0x1b6;
+    const KEY_LEFTALT: u16 = 56;
+    const KEY_BACK: u16 = 158;
+
+    impl EventWriter for Vec<InputEvent> {
+        fn write_events(&mut self, events: &[InputEvent]) -> std::io::Result<()> {
+            self.extend_from_slice(events);
+            Ok(())
+        }
+    }
+
+    fn typed(code: u16) -> Vec<InputEvent> {
+        vec![
+            InputEvent::new(EV_KEY, code, KEY_PRESS),
+            InputEvent::new(EV_SYN, SYN_REPORT, 0),
+            InputEvent::new(EV_KEY, code, KEY_RELEASE),
+            InputEvent::new(EV_SYN, SYN_REPORT, 0),
+        ]
+    }
+
+    fn ev(name: &str) -> Event {
+        Event::from_str(&format!("1 0 {} d", name)).unwrap()
+    }
+
+    #[test]
+    fn key_map() {
+        let m = KeyMap::new([("KEY_OK_HOLD", "KEY_CONTEXT_MENU"), ("A", "KEY_A")]).unwrap();
+        assert_eq!(m.code("KEY_OK"), Some(KEY_OK));
+        assert_eq!(m.code("KEY_OK_HOLD"), Some(KEY_CONTEXT_MENU));
+        assert_eq!(m.code("A"), Some(30));
+        assert_eq!(m.code("KEY_BACK_HOLD"), None);
+        assert_eq!(m.code("BTN_LEFT"), None);
+        assert_eq!(
+            KeyMap::new([("A", "KEY_NOPE")]),
+            Err(String::from("'KEY_NOPE' is not a key name"))
+        );
+        assert!(KeyMap::new([("A", "BTN_LEFT")]).is_err());
+
+        let m = m.with_modifier("KEY_LEFTALT").unwrap();
+        assert_eq!(m.codes("KEY_OK_HOLD"), vec![KEY_CONTEXT_MENU]);
+        assert_eq!(m.codes("KEY_BACK_HOLD2"), vec![KEY_LEFTALT, KEY_BACK]);
+        assert_eq!(m.codes("KEY_UP_DOUBLE"), vec![KEY_LEFTALT, 103]);
+        assert_eq!(m.codes("KEY_OK"), vec![KEY_OK]);
+        assert!(m.codes("MODE_tv").is_empty());
+        assert!(m.codes("KEY_NOPE_HOLD").is_empty());
+        assert!(KeyMap::default().codes("KEY_OK_HOLD").is_empty());
+        assert_eq!(
+            KeyMap::default().with_modifier("ALT"),
+            Err(String::from("'ALT' is not a key name"))
+        );
+    }
+
+    #[test]
+    fn emit() {
+        let m = KeyMap::new([("KEY_OK_HOLD", "KEY_CONTEXT_MENU")]).unwrap();
+        let mut k = Keyboard::new(vec![], m);
+        assert!(k.emit(&ev("KEY_OK")).unwrap());
+        assert!(k.emit(&ev("KEY_OK_HOLD")).unwrap());
+        assert!(!k.emit(&ev("KEY_BACK_HOLD")).unwrap());
+        assert!(!k.emit(&ev("MODE_tv")).unwrap());
+        let mut want = typed(KEY_OK);
+        want.extend(typed(KEY_CONTEXT_MENU));
+        assert_eq!(k.writer, want);
+
+        let m = KeyMap::default().with_modifier("KEY_LEFTALT").unwrap();
+        let mut k = Keyboard::new(vec![], m);
+        assert!(k.emit(&ev("KEY_BACK_HOLD")).unwrap());
+        let syn = InputEvent::new(EV_SYN, SYN_REPORT, 0);
+        assert_eq!(
+            k.writer,
+            vec![
+                InputEvent::new(EV_KEY, KEY_LEFTALT, KEY_PRESS),
+                syn,
+                InputEvent::new(EV_KEY, KEY_BACK, KEY_PRESS),
+                syn,
+                InputEvent::new(EV_KEY, KEY_BACK, KEY_RELEASE),
+                syn,
+                InputEvent::new(EV_KEY, KEY_LEFTALT, KEY_RELEASE),
+                syn,
+            ]
+        );
+    }
+
+    #[test]
+    fn user_dev_size() {
+        let b = user_dev("lirc-changer");
+        assert_eq!(b.len(), 1116);
+        assert_eq!(&b[..13], b"lirc-changer\0");
+        // the name is cut to keep its terminating zero
+        assert_eq!(user_dev(&"x".repeat(100))[79], 0);
+    }
+}